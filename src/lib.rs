@@ -1,28 +1,517 @@
 #![no_std]
-use soroban_sdk::{contract, contractimpl, contracttype, Address, Env, String, Vec, Map, symbol_short};
+use soroban_sdk::{contract, contractclient, contracterror, contractimpl, contracttype, panic_with_error, Address, Bytes, BytesN, Env, IntoVal, String, Symbol, Val, Vec, Map, symbol_short};
+#[cfg(any(test, feature = "testutils"))]
 use soroban_sdk::testutils::arbitrary::std::println;
-use soroban_sdk::token::{Client as TokenClient, StellarAssetClient};
+use soroban_sdk::auth::{ContractContext, InvokerContractAuthEntry, SubContractInvocation};
+use soroban_sdk::token::Client as TokenClient;
+use soroban_sdk::xdr::{FromXdr, ToXdr};
+
+// Debug prints are only available when the host's std is linked in (tests/testutils)
+#[cfg(not(any(test, feature = "testutils")))]
+macro_rules! println {
+    ($($arg:tt)*) => {};
+}
+
+const MAX_METADATA_ENTRIES: u32 = 10;
+const MAX_METADATA_VALUE_LEN: u32 = 128;
+const MAX_ATTACHMENTS: u32 = 5;
+const MAX_DISPLAY_NAME_LEN: u32 = 64;
+const MAX_BIO_LEN: u32 = 256;
+// Lifetime of ephemeral messages in ledgers (about one day at 5s per ledger)
+const DEFAULT_EPHEMERAL_TTL: u32 = 17_280;
+// Consecutive failed charges after which a recurring plan is suspended
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+// Records accepted by a single import_history call
+const MAX_IMPORT_RECORDS: u32 = 50;
+
+// Interface of the admin-configured liquidity pool used to convert between tokens.
+// swap_exact_out pulls at most `max_amount_in` of `token_in` from `from` and sends exactly
+// `amount_out` of `token_out` to `to`, returning the amount of `token_in` spent.
+// swap_exact_in pulls exactly `amount_in` from `from` and sends at least `min_amount_out`
+// to `to`, returning the amount of `token_out` sent; escrows use it because this contract
+// can only authorize a pull from its own balance whose amount is known up front.
+#[contractclient(name = "LiquidityPoolClient")]
+pub trait LiquidityPool {
+    fn swap_exact_out(env: Env, from: Address, to: Address, token_in: Address, token_out: Address, amount_out: i128, max_amount_in: i128) -> i128;
+    fn swap_exact_in(env: Env, from: Address, to: Address, token_in: Address, token_out: Address, amount_in: i128, min_amount_out: i128) -> i128;
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct PriceData {
+    price: i128,
+    timestamp: u64,
+}
+
+// Interface of the admin-configured price oracle. `lastprice` returns the USD value of one
+// whole unit of `asset`, using the same decimals as USD amounts passed to this contract.
+#[contractclient(name = "PriceOracleClient")]
+pub trait PriceOracle {
+    fn lastprice(env: Env, asset: Address) -> Option<PriceData>;
+}
 
 #[contract]
 pub struct PaymentMessagingSystem;
 
+const SECONDS_PER_DAY: u64 = 86_400;
+
+// Civil date (year, month, day) of a day count since 1970-01-01 (proleptic Gregorian)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Day count since 1970-01-01 of a civil date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Structured payment memo mirroring Stellar classic memo types
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Memo {
+    None,
+    Text(String),
+    Id(u64),
+    Hash(BytesN<32>),
+}
+
 #[contracttype]
 #[derive(Clone, Debug)]
 pub struct Payment {
+    from: Address,
+    to: Address,
+    amount: i128,
+    message: Memo,
+    batch_id: Option<u64>,
+    metadata: Option<Map<Symbol, String>>,
+    attachments: Vec<BytesN<32>>,
+}
+
+// Payment record layout of the original per-address history vectors, read only for migration
+#[contracttype]
+#[derive(Clone, Debug)]
+struct PaymentV1 {
     from: Address,
     to: Address,
     amount: i128,
     message: String,
 }
 
+// Where payment records go: `Events` skips persisting them for deployments that index via RPC
+#[contracttype]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryMode {
+    Events,
+    Storage,
+    Both,
+}
+
 #[contracttype]
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryVisibility {
+    Public,
+    Private,
+}
+
+// When a recurring plan pays: every N seconds, monthly on a day of the month (1-31),
+// or weekly on a weekday (0 = Monday ... 6 = Sunday)
+#[contracttype]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    EverySeconds(u64),
+    Monthly(u32),
+    Weekly(u32),
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
 pub struct RecurringPayment {
     to: Address,
     amount: i128,
-    interval: u64,
+    schedule: Schedule,
     message: String,
     last_payment: u64,
+    payout_token: Option<Address>,
+    max_deposit: i128,
+    use_deposit: bool,
+    // Token the plan must be processed in, pinned while it is funded from a deposit or a
+    // prepaid balance so nobody can run up its failure count by charging it in another token
+    pinned_token: Option<Address>,
+    failed_attempts: u32,
+    // Earliest time a failed plan is due again: the next scheduled time after the failure, so
+    // each due cycle adds at most one failed attempt
+    retry_at: u64,
+    suspended: bool,
+    keeper_reward: KeeperReward,
+    processor: Option<Address>,
+    // Additional (token, amount) legs paid to `to` in the same cycle as `amount`
+    extra_legs: Vec<(Address, i128)>,
+}
+
+// Reward paid from a recurring plan's sender to whoever processes it, in the processing
+// token; Bps is a share of the amount charged in that token
+#[contracttype]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeeperReward {
+    None,
+    Flat(i128),
+    Bps(u32),
+}
+
+// Optional extras of a transfer: a 32-byte key making retries safe (carried as Bytes, since a
+// contract type cannot hold an optional BytesN), structured metadata and attachment hashes
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct TransferOptions {
+    idempotency_key: Option<Bytes>,
+    metadata: Option<Map<Symbol, String>>,
+    attachments: Vec<BytesN<32>>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct TransferIntent {
+    token_id: Address,
+    payment: Payment,
+    unlock_at: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct SpenderAllowance {
+    token_id: Address,
+    limit: i128,
+    spent: i128,
+    expiry: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct PaymentLink {
+    from: Address,
+    token_id: Address,
+    amount: i128,
+    secret_hash: BytesN<32>,
+    expiry: u64,
+    claimed: bool,
+    payout_token: Option<Address>,
+    min_payout_bps: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Htlc {
+    sender: Address,
+    receiver: Address,
+    token_id: Address,
+    amount: i128,
+    hashlock: BytesN<32>,
+    timelock: u64,
+    preimage: Option<Bytes>,
+    refunded: bool,
+    payout_token: Option<Address>,
+    min_payout_bps: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Campaign {
+    owner: Address,
+    token_id: Address,
+    goal: i128,
+    deadline: u64,
+    description: String,
+    raised: i128,
+    withdrawn: bool,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Donation {
+    from: Address,
+    amount: i128,
+    message: String,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Payroll {
+    employer: Address,
+    token_id: Address,
+    plans: Vec<RecurringPayment>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    max_transfers: u32,
+    window_ledgers: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct RateLimitStatus {
+    used: u32,
+    max_transfers: u32,
+    window_start: u32,
+    window_ledgers: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct InheritancePlan {
+    beneficiaries: Vec<(Address, u32)>,
+    inactivity_secs: u64,
+    last_active: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct SessionKey {
+    allowed_ops: Vec<Symbol>,
+    expiry: u64,
+    max_amount: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct PaymentRequest {
+    requester: Address,
+    payer: Address,
+    token_id: Address,
+    amount: i128,
+    paid: i128,
+    message: String,
+    fulfilled: bool,
+    due_date: Option<u64>,
+    grace_period: u64,
+    late_fee_bps: u32,
+    late_fees_paid: i128,
+    usd_denominated: bool,
+    metadata: Option<Map<Symbol, String>>,
+}
+
+// Optional extras of a payment request: due date terms (after `due_date + grace_period`
+// every installment is charged `late_fee_bps` on top) and structured metadata, which is
+// copied onto the payments that settle the request
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct RequestOptions {
+    due_date: Option<u64>,
+    grace_period: u64,
+    late_fee_bps: u32,
+    metadata: Option<Map<Symbol, String>>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Default)]
+pub struct ReputationScore {
+    completed_escrows: u32,
+    on_time_recurring: u32,
+    fulfilled_requests: u32,
+    late_payments: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Message {
+    from: Address,
+    text: String,
+    sent_at: u64,
+    attachments: Vec<BytesN<32>>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Gift {
+    from: Address,
+    to: Address,
+    token_id: Address,
+    amount: i128,
+    unlock_at: u64,
+    hidden_message: String,
+    claimed: bool,
+    payout_token: Option<Address>,
+    min_payout_bps: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Profile {
+    display_name: String,
+    avatar_hash: BytesN<32>,
+    bio: String,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    Proposed,
+    Active,
+    Cancelled,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    merchant: Address,
+    subscriber: Address,
+    token_id: Address,
+    amount: i128,
+    interval: u64,
+    status: SubscriptionStatus,
+    next_charge_at: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Quote {
+    fee: i128,
+    net_amount: i128,
+    allowed: bool,
+    reason: Symbol,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Airdrop {
+    funder: Address,
+    token_id: Address,
+    merkle_root: BytesN<32>,
+    remaining: i128,
+    expiry: u64,
+    payout_token: Option<Address>,
+    min_payout_bps: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct GlobalStats {
+    total_payments: u64,
+    volume: Map<Address, i128>,
+    active_plans: u32,
+    open_escrows: u32,
+}
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Error {
+    Frozen = 1,
+    TooManyRecipients = 2,
+    MessageTooLong = 3,
+    BatchTooLarge = 4,
+}
+
+// Cross-contract check gating a conditional payment: `function(args)` on `contract`
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Condition {
+    contract: Address,
+    function: Symbol,
+    args: Vec<Val>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct ConditionalPayment {
+    from: Address,
+    to: Address,
+    token_id: Address,
+    amount: i128,
+    condition_contract: Address,
+    condition_fn: Symbol,
+    args: Vec<Val>,
+    expiry: u64,
+    settled: bool,
+    payout_token: Option<Address>,
+    min_payout_bps: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct SplitRoute {
+    owner: Address,
+    receivers: Vec<(Address, u32)>,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferStatus {
+    Sent,
+    Invalid,
+    Frozen,
+    RateLimited,
+    NeedsConfirmation,
+    Failed,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Limits {
+    max_recipients_per_batch: u32,
+    max_message_len: u32,
+    max_history_per_tx: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct Voucher {
+    issuer: Address,
+    token_id: Address,
+    amount: i128,
+    expiry: u64,
+    redeemed: bool,
+    payout_token: Option<Address>,
+    min_payout_bps: u32,
+}
+
+// Payment a sender authorizes for a relayer to submit through transfer_sponsored
+#[contracttype]
+#[derive(Clone, Debug)]
+pub struct SponsoredTransfer {
+    to: Address,
+    token_id: Address,
+    amount: i128,
+    memo: Memo,
+    tip: i128,
+}
+
+// Result of transfer_v2. `fee` is the protocol fee charged to the sender on top of the amount
+// (on confirmation for a held transfer), and `net_amount` is the amount the recipient
+// receives (or will receive once a held transfer is confirmed).
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransferReceipt {
+    payment_id: Option<u64>,
+    intent_id: Option<u64>,
+    net_amount: i128,
+    fee: i128,
+    timestamp: u64,
 }
 
 #[contractimpl]
@@ -36,63 +525,227 @@ impl PaymentMessagingSystem {
         balance
     }
 
-    // XLM transfer and message sending
-    pub fn transfer(env: Env, token_id: Address, from: Address, to: Address, amount: i128, message: String) -> bool {
-        from.require_auth();
+    // Balance query without auth, unless the deployment has made balances private
+    pub fn balance_of(env: Env, token_id: Address, address: Address) -> i128 {
+        if Self::private_balances(&env) {
+            address.require_auth();
+        }
         let token = TokenClient::new(&env, &token_id);
+        token.balance(&address)
+    }
 
-        println!("Initiating transfer: From: {:?}, To: {:?}, Amount: {:?}, Message: {:?}", from, to, amount, message); // Debug print
+    // Balances of several tokens for one address in a single call
+    pub fn get_balances(env: Env, address: Address, tokens: Vec<Address>) -> Map<Address, i128> {
+        if Self::private_balances(&env) {
+            address.require_auth();
+        }
+        let mut balances = Map::new(&env);
+        for token_id in tokens.iter() {
+            let token = TokenClient::new(&env, &token_id);
+            balances.set(token_id.clone(), token.balance(&address));
+        }
+        balances
+    }
 
-        token.transfer(&from, &to, &amount);
+    // Require the owner's auth for balance_of (admin only)
+    pub fn set_private_balances(env: Env, private: bool) {
+        Self::require_admin(&env);
+        env.storage().persistent().set(&symbol_short!("priv_bal"), &private);
+        println!("Private balances set: {:?}", private); // Debug print
+    }
 
-        // Store payment record
-        let mut payments = Self::get_payments(&env, &from);
-        payments.push_back(Payment {
-            from: from.clone(),
-            to: to.clone(),
-            amount,
-            message: message.clone(),
-        });
-        Self::set_payments(&env, &from, &payments);
+    // XLM transfer and message sending. Returns false when the transfer is held for
+    // confirmation; its intent ID is listed by get_pending_intents (or returned by transfer_v2).
+    pub fn transfer(env: Env, token_id: Address, from: Address, to: Address, amount: i128, message: String, options: Option<TransferOptions>) -> bool {
+        Self::transfer_with_memo(env, token_id, from, to, amount, Memo::Text(message), options)
+    }
 
-        println!("Transfer successful: From: {:?}, To: {:?}, Amount: {:?}", from, to, amount); // Debug print
+    // transfer with a structured memo, e.g. the deposit ID an exchange credits the payment to
+    pub fn transfer_with_memo(env: Env, token_id: Address, from: Address, to: Address, amount: i128, memo: Memo, options: Option<TransferOptions>) -> bool {
+        Self::execute_transfer(&env, token_id, from, to, amount, memo, options).payment_id.is_some()
+    }
+
+    // transfer returning the payment ID (or the intent ID of a held transfer) and the amounts
+    // involved, so integrators can reconcile from the result alone
+    pub fn transfer_v2(env: Env, token_id: Address, from: Address, to: Address, amount: i128, message: String, options: Option<TransferOptions>) -> TransferReceipt {
+        Self::transfer_v2_with_memo(env, token_id, from, to, amount, Memo::Text(message), options)
+    }
+
+    // transfer_v2 with a structured memo
+    pub fn transfer_v2_with_memo(env: Env, token_id: Address, from: Address, to: Address, amount: i128, memo: Memo, options: Option<TransferOptions>) -> TransferReceipt {
+        Self::execute_transfer(&env, token_id, from, to, amount, memo, options)
+    }
+
+    // Round the owner's transfers up to a multiple of `round_to`, donating the difference to `charity`
+    pub fn set_roundup(env: Env, owner: Address, charity: Address, round_to: i128) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if round_to <= 1 {
+            panic!("round_to must be greater than 1");
+        }
+        env.storage().persistent().set(&(symbol_short!("roundup"), owner.clone()), &(charity.clone(), round_to));
+        println!("Round-up set: Owner: {:?}, Charity: {:?}, Round to: {:?}", owner, charity, round_to); // Debug print
+    }
+
+    // Stop rounding up the owner's transfers
+    pub fn clear_roundup(env: Env, owner: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().remove(&(symbol_short!("roundup"), owner));
+    }
+
+    // The owner's (charity, round_to), if round-ups are enabled
+    pub fn get_roundup(env: Env, owner: Address) -> Option<(Address, i128)> {
+        env.storage().persistent().get(&(symbol_short!("roundup"), owner))
+    }
+
+    // Hold the owner's transfers of `token_id` above `amount` for confirmation after `delay` seconds.
+    // Outgoing payments that cannot be held (batches, deposits, escrows, ...) above it are rejected.
+    pub fn set_confirmation_threshold(env: Env, owner: Address, token_id: Address, amount: i128, delay: u64) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        let key = (symbol_short!("confirm"), owner.clone(), token_id.clone());
+        env.storage().persistent().set(&key, &(amount, delay));
+        println!("Confirmation threshold set: Owner: {:?}, Token ID: {:?}, Amount: {:?}, Delay: {:?}", owner, token_id, amount, delay); // Debug print
+    }
+
+    // The owner's (threshold, delay) for `token_id`, if set
+    pub fn get_confirmation_threshold(env: Env, owner: Address, token_id: Address) -> Option<(i128, u64)> {
+        env.storage().persistent().get(&(symbol_short!("confirm"), owner, token_id))
+    }
+
+    // Execute a pending transfer once its delay has passed
+    pub fn confirm_transfer(env: Env, intent_id: u64) -> bool {
+        let intent = Self::get_transfer_intent(env.clone(), intent_id);
+        let from = intent.payment.from.clone();
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        Self::require_not_frozen(&env, &intent.payment.to);
+        if env.ledger().timestamp() < intent.unlock_at {
+            panic!("confirmation delay not elapsed");
+        }
+        Self::remove_transfer_intent(&env, &from, intent_id);
+
+        let token = TokenClient::new(&env, &intent.token_id);
+        token.transfer(&from, &intent.payment.to, &intent.payment.amount);
+        Self::charge_fee(&env, &intent.token_id, &from, intent.payment.amount);
+        Self::check_alert(&env, &intent.token_id, &from, &intent.payment.to, intent.payment.amount);
+        Self::record_payment(&env, &intent.token_id, intent.payment.clone());
+        println!("Transfer confirmed: Intent: {:?}, From: {:?}, Amount: {:?}", intent_id, from, intent.payment.amount); // Debug print
         true
     }
 
+    // Drop a pending transfer
+    pub fn cancel_transfer(env: Env, intent_id: u64) {
+        let intent = Self::get_transfer_intent(env.clone(), intent_id);
+        intent.payment.from.require_auth();
+        Self::record_activity(&env, &intent.payment.from);
+        Self::remove_transfer_intent(&env, &intent.payment.from, intent_id);
+        println!("Transfer cancelled: Intent: {:?}", intent_id); // Debug print
+    }
+
+    // View a pending transfer
+    pub fn get_transfer_intent(env: Env, intent_id: u64) -> TransferIntent {
+        env.storage().persistent().get(&(symbol_short!("intent"), intent_id)).unwrap_or_else(|| panic!("transfer intent not found"))
+    }
+
+    // IDs of the owner's transfers waiting for confirmation, oldest first
+    pub fn get_pending_intents(env: Env, owner: Address) -> Vec<u64> {
+        env.storage().persistent().get(&(symbol_short!("intents"), owner)).unwrap_or_else(|| Vec::new(&env))
+    }
+
     // Create payment plan for recurring payments
     pub fn create_recurring_payment(env: Env, from: Address, to: Address, amount: i128, interval: u64, message: String) {
         from.require_auth();
+        Self::record_activity(&env, &from);
         let mut recurring_payments = Self::get_recurring_payments(&env);
+        // Replacing a suspended plan (or creating the first one) adds an active plan
+        if recurring_payments.get(from.clone()).map(|plan| plan.suspended).unwrap_or(true) {
+            Self::update_stats(&env, |stats| stats.active_plans += 1);
+        }
         recurring_payments.set(from.clone(), RecurringPayment {
             to: to.clone(),
             amount,
-            interval,
+            schedule: Schedule::EverySeconds(interval),
             message: message.clone(),
             last_payment: env.ledger().timestamp(),
+            payout_token: None,
+            max_deposit: 0,
+            use_deposit: false,
+            pinned_token: None,
+            failed_attempts: 0,
+            retry_at: 0,
+            suspended: false,
+            keeper_reward: KeeperReward::None,
+            processor: None,
+            extra_legs: Vec::new(&env),
         });
         Self::set_recurring_payments(&env, &recurring_payments);
         println!("Recurring payment created: From: {:?}, To: {:?}, Amount: {:?}, Interval: {:?}, Message: {:?}", from, to, amount, interval, message); // Debug print
     }
 
+    // Settle the sender's recurring plan in `payout_token`, spending at most `max_deposit`
+    // of the processing token per payment through the configured liquidity pool. Escrows
+    // are converted with set_escrow_payout_token instead.
+    pub fn set_recurring_payout_token(env: Env, from: Address, payout_token: Address, max_deposit: i128) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        if max_deposit <= 0 {
+            panic!("max deposit must be positive");
+        }
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        if payment.use_deposit {
+            panic!("plan is funded from deposit");
+        }
+        if !payment.extra_legs.is_empty() {
+            panic!("plan pays a multi-asset bundle");
+        }
+        payment.payout_token = Some(payout_token.clone());
+        payment.max_deposit = max_deposit;
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Recurring payout token set: From: {:?}, Payout token: {:?}, Max deposit: {:?}", from, payout_token, max_deposit); // Debug print
+    }
+
+    // Set the liquidity pool used for token conversion (admin only)
+    pub fn set_liquidity_pool(env: Env, pool: Address) {
+        Self::require_admin(&env);
+        env.storage().persistent().set(&symbol_short!("pool"), &pool);
+        println!("Liquidity pool set: {:?}", pool); // Debug print
+    }
+
     // Multi-recipient transfer
-    pub fn multi_transfer(env: Env, token_id: Address, from: Address, recipients: Vec<(Address, i128)>, message: String) -> bool {
+    pub fn multi_transfer(env: Env, token_id: Address, from: Address, recipients: Vec<(Address, i128)>, memo: Memo, idempotency_key: Option<BytesN<32>>) -> bool {
         from.require_auth();
+        Self::require_within_limit(&env, recipients.len(), |limits| limits.max_recipients_per_batch, Error::TooManyRecipients);
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &from);
+        Self::record_activity(&env, &from);
+        Self::use_idempotency_key(&env, &from, idempotency_key);
+        Self::consume_rate_limit(&env, &from, recipients.len());
+        let total = recipients.iter().fold(0i128, |total, (_, amount)| total + amount);
+        Self::require_no_confirmation(&env, &from, &token_id, total);
         let token = TokenClient::new(&env, &token_id);
 
-        println!("Initiating multi-transfer: From: {:?}, Recipients: {:?}, Message: {:?}", from, recipients, message); // Debug print
+        println!("Initiating multi-transfer: From: {:?}, Recipients: {:?}, Memo: {:?}", from, recipients, memo); // Debug print
 
         for (to, amount) in recipients.iter() {
+            Self::require_not_frozen(&env, &to);
             token.transfer(&from, &to, &amount);
+            Self::check_alert(&env, &token_id, &from, &to, amount);
 
             // Store payment record
-            let mut payments = Self::get_payments(&env, &from);
-            payments.push_back(Payment {
+            Self::record_payment(&env, &token_id, Payment {
                 from: from.clone(),
                 to: to.clone(),
-                amount: amount, // Dereference the amount
-                message: message.clone(),
+                amount,
+                message: memo.clone(),
+                batch_id: None,
+                metadata: None,
+                attachments: Vec::new(&env),
             });
-            Self::set_payments(&env, &from, &payments);
             println!("Transferred: From: {:?}, To: {:?}, Amount: {:?}", from, to, amount); // Debug print
         }
 
@@ -100,168 +753,5006 @@ impl PaymentMessagingSystem {
         true
     }
 
-    // View transaction history
+    // View transaction history. With a history limit set only the most recent entries are
+    // returned; older ones are read with get_transaction_history_page.
     pub fn get_transaction_history(env: Env, address: Address) -> Vec<Payment> {
-        address.require_auth();
-        let history = Self::get_payments(&env, &address);
+        Self::require_history_access(&env, &address);
+        let len = Self::get_history_len(&env, &address);
+        let start = Self::get_limits(env.clone()).map_or(0, |limits| len.saturating_sub(limits.max_history_per_tx));
+        let history = Self::get_payments_range(&env, &address, start, len);
         println!("Transaction history for: {:?}, History: {:?}", address, history); // Debug print
         history
     }
 
-    // Helper functions
-    fn get_payments(env: &Env, address: &Address) -> Vec<Payment> {
-        let key = (symbol_short!("payments"), address.clone());
-        env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env))
+    // Up to `limit` history entries starting at position `start`
+    pub fn get_transaction_history_page(env: Env, address: Address, start: u32, limit: u32) -> Vec<Payment> {
+        Self::require_history_access(&env, &address);
+        Self::require_within_limit(&env, limit, |limits| limits.max_history_per_tx, Error::BatchTooLarge);
+        Self::get_payments_range(&env, &address, start, start.saturating_add(limit))
     }
 
-    fn set_payments(env: &Env, address: &Address, payments: &Vec<Payment>) {
-        let key = (symbol_short!("payments"), address.clone());
-        env.storage().persistent().set(&key, payments);
+    // Look up any recorded payment by its global ID, whichever party is querying
+    pub fn get_payment(env: Env, payment_id: u64) -> Payment {
+        let key = (symbol_short!("payment"), payment_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("payment not found"))
     }
 
-    fn get_recurring_payments(env: &Env) -> Map<Address, RecurringPayment> {
-        env.storage().persistent().get(&symbol_short!("recurring")).unwrap_or_else(|| Map::new(env))
+    // Let anyone list the owner's payment history, or only the owner (the default).
+    // Single records stay readable by ID through get_payment either way.
+    pub fn set_history_visibility(env: Env, owner: Address, visibility: HistoryVisibility) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().set(&(symbol_short!("hist_vis"), owner.clone()), &visibility);
+        println!("History visibility set: Owner: {:?}, Visibility: {:?}", owner, visibility); // Debug print
     }
 
-    fn set_recurring_payments(env: &Env, recurring_payments: &Map<Address, RecurringPayment>) {
-        env.storage().persistent().set(&symbol_short!("recurring"), recurring_payments);
+    pub fn get_history_visibility(env: Env, owner: Address) -> HistoryVisibility {
+        env.storage().persistent().get(&(symbol_short!("hist_vis"), owner)).unwrap_or(HistoryVisibility::Private)
     }
 
-    // Process recurring payments
-    pub fn process_recurring_payments(env: Env, token_id: Address) {
-        let current_timestamp = env.ledger().timestamp();
-        let mut recurring_payments = Self::get_recurring_payments(&env);
-        let token = TokenClient::new(&env, &token_id);
+    // Choose whether payment history is persisted, emitted as events, or both (admin only)
+    pub fn set_history_mode(env: Env, mode: HistoryMode) {
+        Self::require_admin(&env);
+        env.storage().persistent().set(&symbol_short!("hist_mode"), &mode);
+        println!("History mode set: {:?}", mode); // Debug print
+    }
 
-        println!("Processing recurring payments at timestamp: {:?}", current_timestamp); // Debug print
+    pub fn get_history_mode(env: Env) -> HistoryMode {
+        env.storage().persistent().get(&symbol_short!("hist_mode")).unwrap_or(HistoryMode::Storage)
+    }
 
-        for (from, mut payment) in recurring_payments.iter() {
-            if current_timestamp >= payment.last_payment + payment.interval {
-                // Perform the payment
+    // Move up to `batch_size` of the owner's v1 history entries (a vector of full payment records)
+    // into the v2 layout (a vector of payment IDs), newest first, so reads stay in order between
+    // calls (admin only). Returns how many v1 entries remain; calling again once 0 is a no-op.
+    pub fn migrate_v1_to_v2(env: Env, owner: Address, batch_size: u32) -> u32 {
+        Self::require_admin(&env);
+        Self::require_within_limit(&env, batch_size, |limits| limits.max_history_per_tx, Error::BatchTooLarge);
+        let mut legacy = Self::get_legacy_payments(&env, &owner);
+        let mut ids = Self::get_payment_ids(&env, &owner);
+        for _ in 0..batch_size {
+            let Some(payment) = legacy.pop_back() else { break };
+            let payment_id = Self::next_id(&env, symbol_short!("pay_id"));
+            env.storage().persistent().set(&(symbol_short!("payment"), payment_id), &Self::upgrade_payment(&env, payment));
+            ids.push_front(payment_id);
+        }
+        let key = (symbol_short!("payments"), owner.clone());
+        if legacy.is_empty() {
+            env.storage().persistent().remove(&key);
+        } else {
+            env.storage().persistent().set(&key, &legacy);
+        }
+        env.storage().persistent().set(&(symbol_short!("pay_ids"), owner.clone()), &ids);
+        println!("History migrated: Owner: {:?}, Remaining: {:?}", owner, legacy.len()); // Debug print
+        legacy.len()
+    }
+
+    // Helper functions
+    // History entries at positions [start, end): unmigrated v1 entries first, then records
+    // referenced by ID. Only the records in the range are loaded.
+    fn get_payments_range(env: &Env, address: &Address, start: u32, end: u32) -> Vec<Payment> {
+        let legacy = Self::get_legacy_payments(env, address);
+        let ids = Self::get_payment_ids(env, address);
+        let end = end.min(legacy.len() + ids.len());
+        let mut payments = Vec::new(env);
+        for i in start..end {
+            if i < legacy.len() {
+                payments.push_back(Self::upgrade_payment(env, legacy.get(i).unwrap()));
+            } else if let Some(payment) = env.storage().persistent().get(&(symbol_short!("payment"), ids.get(i - legacy.len()).unwrap())) {
+                payments.push_back(payment);
+            }
+        }
+        payments
+    }
+
+    fn get_history_len(env: &Env, address: &Address) -> u32 {
+        Self::get_legacy_payments(env, address).len() + Self::get_payment_ids(env, address).len()
+    }
+
+    fn get_legacy_payments(env: &Env, address: &Address) -> Vec<PaymentV1> {
+        let key = (symbol_short!("payments"), address.clone());
+        env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env))
+    }
+
+    fn upgrade_payment(env: &Env, payment: PaymentV1) -> Payment {
+        Payment {
+            from: payment.from,
+            to: payment.to,
+            amount: payment.amount,
+            message: Memo::Text(payment.message),
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(env),
+        }
+    }
+
+    fn get_payment_ids(env: &Env, address: &Address) -> Vec<u64> {
+        let key = (symbol_short!("pay_ids"), address.clone());
+        env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env))
+    }
+
+    // Private histories can only be read with the owner's auth
+    fn require_history_access(env: &Env, owner: &Address) {
+        if Self::get_history_visibility(env.clone(), owner.clone()) == HistoryVisibility::Private {
+            owner.require_auth();
+        }
+    }
+
+    // Append a payment to the sender's history and the global registry, returning its ID
+    fn record_payment(env: &Env, token_id: &Address, payment: Payment) -> u64 {
+        Self::update_stats(env, |stats| {
+            stats.total_payments += 1;
+            stats.volume.set(token_id.clone(), stats.volume.get(token_id.clone()).unwrap_or(0) + payment.amount);
+        });
+        Self::update_net_position(env, &payment.from, &payment.to, token_id, payment.amount);
+        let payment_id = Self::store_payment(env, payment, symbol_short!("payment"));
+        // Token and time are kept beside the record so a receipt can be issued for it later
+        if Self::get_history_mode(env.clone()) != HistoryMode::Events {
+            env.storage().persistent().set(&(symbol_short!("pay_info"), payment_id), &(token_id.clone(), env.ledger().timestamp()));
+        }
+        payment_id
+    }
+
+    // Persist and/or announce a record under `topic` according to the history mode
+    fn store_payment(env: &Env, payment: Payment, topic: Symbol) -> u64 {
+        let payment_id = Self::next_id(env, symbol_short!("pay_id"));
+        let mode = Self::get_history_mode(env.clone());
+        if mode != HistoryMode::Storage {
+            env.events().publish((topic, payment.from.clone(), payment.to.clone()), (payment_id, payment.clone()));
+        }
+        if mode == HistoryMode::Events {
+            return payment_id;
+        }
+        env.storage().persistent().set(&(symbol_short!("payment"), payment_id), &payment);
+
+        let key = (symbol_short!("pay_ids"), payment.from.clone());
+        let mut ids = Self::get_payment_ids(env, &payment.from);
+        ids.push_back(payment_id);
+        env.storage().persistent().set(&key, &ids);
+        payment_id
+    }
+
+    // Reject a submission whose key the sender has already used
+    fn use_idempotency_key(env: &Env, from: &Address, idempotency_key: Option<BytesN<32>>) {
+        if let Some(idempotency_key) = idempotency_key {
+            let key = (symbol_short!("idem"), from.clone(), idempotency_key);
+            if env.storage().persistent().has(&key) {
+                panic!("duplicate submission");
+            }
+            env.storage().persistent().set(&key, &true);
+        }
+    }
+
+    // Pay one due plan, returning the amount of `token_id` charged, or None instead of trapping
+    // if the payment cannot be made. A sufficient prepaid balance is used first; deposit-funded
+    // plans need no sender signature either; others are converted through the pool if the plan
+    // pays out in another token.
+    fn try_charge_plan(env: &Env, token_id: &Address, from: &Address, payment: &RecurringPayment) -> Option<i128> {
+        if Self::is_frozen(env.clone(), from.clone()) || Self::is_frozen(env.clone(), payment.to.clone()) {
+            return None;
+        }
+        if !payment.extra_legs.is_empty() {
+            return Self::try_charge_bundle(env, token_id, from, payment).then_some(payment.amount);
+        }
+        if Self::covered_by_prepaid(env, token_id, from, payment) {
+            return Self::try_pay_prepaid(env, token_id, from, &payment.to, payment.amount).then_some(payment.amount);
+        }
+        match payment.payout_token.clone() {
+            Some(payout_token) if payout_token != *token_id && !payment.use_deposit => {
                 from.require_auth();
-                token.transfer(&from, &payment.to, &payment.amount);
+                let pool = LiquidityPoolClient::new(env, &Self::get_liquidity_pool(env));
+                pool.try_swap_exact_out(from, &payment.to, token_id, &payout_token, &payment.amount, &payment.max_deposit)
+                    .ok()
+                    .and_then(|spent| spent.ok())
+            }
+            _ => Self::try_pay_from(env, token_id, from, payment.use_deposit, &payment.to, payment.amount).then_some(payment.amount),
+        }
+    }
+
+    // Whether the next charge of a plan in `token_id` comes out of its prepaid balance
+    fn covered_by_prepaid(env: &Env, token_id: &Address, from: &Address, payment: &RecurringPayment) -> bool {
+        let pays_in_token = payment.use_deposit || payment.payout_token.as_ref().is_none_or(|payout_token| payout_token == token_id);
+        payment.extra_legs.is_empty() && pays_in_token && Self::get_prepaid(env.clone(), from.clone(), token_id.clone()) >= payment.amount
+    }
+
+    // Pay `amount` out of the sender's prepaid balance, returning false if it fails
+    fn try_pay_prepaid(env: &Env, token_id: &Address, from: &Address, to: &Address, amount: i128) -> bool {
+        let prepaid = Self::get_prepaid(env.clone(), from.clone(), token_id.clone());
+        if prepaid < amount {
+            return false;
+        }
+        let token = TokenClient::new(env, token_id);
+        if token.try_transfer(&env.current_contract_address(), to, &amount).is_err() {
+            return false;
+        }
+        env.storage().persistent().set(&(symbol_short!("prepaid"), from.clone(), token_id.clone()), &(prepaid - amount));
+        true
+    }
+
+    // Whether a plan may be processed in `token_id`; plans pinned to a token only run in that token
+    fn charges_in(payment: &RecurringPayment, token_id: &Address) -> bool {
+        payment.pinned_token.as_ref().is_none_or(|pinned_token| pinned_token == token_id)
+    }
+
+    // Pay `amount` from the sender's internal deposit or wallet, returning false if it fails
+    fn try_pay_from(env: &Env, token_id: &Address, from: &Address, use_deposit: bool, to: &Address, amount: i128) -> bool {
+        let token = TokenClient::new(env, token_id);
+        if !use_deposit {
+            from.require_auth();
+            return token.try_transfer(from, to, &amount).is_ok();
+        }
+        if Self::get_deposit(env.clone(), from.clone(), token_id.clone()) < amount {
+            return false;
+        }
+        if token.try_transfer(&env.current_contract_address(), to, &amount).is_err() {
+            return false;
+        }
+        Self::debit_deposit(env, from, token_id, amount);
+        true
+    }
+
+    // Charge a due plan and update it; a failure is recorded on the plan instead of trapping
+    fn process_plan(env: &Env, token_id: &Address, from: &Address, payment: &mut RecurringPayment) -> Option<i128> {
+        let Some(charged) = Self::try_charge_plan(env, token_id, from, payment) else {
+            payment.failed_attempts += 1;
+            payment.retry_at = Self::due_after(&payment.schedule, env.ledger().timestamp());
+            if payment.failed_attempts >= MAX_FAILED_ATTEMPTS && !payment.suspended {
+                payment.suspended = true;
+                Self::update_stats(env, |stats| stats.active_plans = stats.active_plans.saturating_sub(1));
+            }
+            env.events().publish((symbol_short!("rec_fail"), from.clone()), (payment.failed_attempts, payment.suspended));
+            println!("Recurring payment failed: From: {:?}, Attempts: {:?}, Suspended: {:?}", from, payment.failed_attempts, payment.suspended); // Debug print
+            return None;
+        };
+
+        // Update last payment time
+        payment.last_payment = env.ledger().timestamp();
+        payment.failed_attempts = 0;
+        payment.retry_at = 0;
+        Self::update_reputation(env, from, |score| score.on_time_recurring += 1);
+        let paid_token = match payment.payout_token.clone() {
+            Some(payout_token) if !payment.use_deposit => payout_token,
+            _ => token_id.clone(),
+        };
+
+        // Store payment records, linking the legs of a bundle through a shared batch ID
+        let batch_id = if payment.extra_legs.is_empty() { None } else { Some(Self::next_id(env, symbol_short!("batch_id"))) };
+        let mut legs = Vec::from_array(env, [(paid_token, payment.amount)]);
+        legs.append(&payment.extra_legs);
+        for (leg_token, leg_amount) in legs.iter() {
+            Self::check_alert(env, &leg_token, from, &payment.to, leg_amount);
+            Self::record_payment(env, &leg_token, Payment {
+                from: from.clone(),
+                to: payment.to.clone(),
+                amount: leg_amount,
+                message: Memo::Text(payment.message.clone()),
+                batch_id,
+                metadata: None,
+                attachments: Vec::new(env),
+            });
+        }
+
+        println!("Processed recurring payment: From: {:?}, To: {:?}, Amount: {:?}", from, payment.to, payment.amount); // Debug print
+        Some(charged)
+    }
+
+    // Timestamp at which a plan is next due after its last payment, or after its last failure
+    fn next_due(payment: &RecurringPayment) -> u64 {
+        Self::due_after(&payment.schedule, payment.last_payment).max(payment.retry_at)
+    }
+
+    // First scheduled time after `last`. Calendar schedules are due at 00:00 UTC on the
+    // matching day; a monthly day past the end of a month falls on its last day.
+    fn due_after(schedule: &Schedule, last: u64) -> u64 {
+        let last_day = (last / SECONDS_PER_DAY) as i64;
+        let due_day = match *schedule {
+            Schedule::EverySeconds(seconds) => return last + seconds,
+            Schedule::Weekly(weekday) => {
+                // Day 0 (1970-01-01) was a Thursday, i.e. weekday 3 counting from Monday
+                let next_day = last_day + 1;
+                let next_weekday = (next_day + 3).rem_euclid(7);
+                next_day + (weekday as i64 - next_weekday).rem_euclid(7)
+            }
+            Schedule::Monthly(day_of_month) => {
+                let (year, month, day) = civil_from_days(last_day);
+                let this_month = day_of_month.min(days_in_month(year, month));
+                if day < this_month {
+                    days_from_civil(year, month, this_month)
+                } else {
+                    let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                    days_from_civil(year, month, day_of_month.min(days_in_month(year, month)))
+                }
+            }
+        };
+        due_day as u64 * SECONDS_PER_DAY
+    }
+
+    fn get_recurring_payments(env: &Env) -> Map<Address, RecurringPayment> {
+        env.storage().persistent().get(&symbol_short!("recurring")).unwrap_or_else(|| Map::new(env))
+    }
+
+    fn set_recurring_payments(env: &Env, recurring_payments: &Map<Address, RecurringPayment>) {
+        env.storage().persistent().set(&symbol_short!("recurring"), recurring_payments);
+    }
+
+    // Process recurring payments
+    pub fn process_recurring_payments(env: Env, token_id: Address) {
+        let current_timestamp = env.ledger().timestamp();
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        Self::require_within_limit(&env, recurring_payments.len(), |limits| limits.max_history_per_tx, Error::BatchTooLarge);
+
+        println!("Processing recurring payments at timestamp: {:?}", current_timestamp); // Debug print
+
+        for (from, mut payment) in recurring_payments.iter() {
+            if !payment.suspended && payment.processor.is_none() && Self::charges_in(&payment, &token_id) && current_timestamp >= Self::next_due(&payment) {
+                Self::process_plan(&env, &token_id, &from, &mut payment);
+                recurring_payments.set(from.clone(), payment);
+            }
+        }
+
+        Self::set_recurring_payments(&env, &recurring_payments);
+    }
+
+    // Process up to `max` plans starting at `cursor`, returning the cursor for the next batch
+    // (None once every plan has been visited). `max` must be positive so the cursor advances.
+    pub fn process_recurring_batch(env: Env, token_id: Address, cursor: Option<Address>, max: u32) -> Option<Address> {
+        if max == 0 {
+            panic!("max must be positive");
+        }
+        let current_timestamp = env.ledger().timestamp();
+        Self::require_within_limit(&env, max, |limits| limits.max_history_per_tx, Error::BatchTooLarge);
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let senders = recurring_payments.keys();
+        let start = match cursor {
+            Some(cursor) => match senders.binary_search(&cursor) {
+                Ok(i) | Err(i) => i,
+            },
+            None => 0,
+        };
+        let end = senders.len().min(start.saturating_add(max));
+
+        for i in start..end {
+            let from = senders.get(i).unwrap();
+            let mut payment = recurring_payments.get(from.clone()).unwrap();
+            if !payment.suspended && payment.processor.is_none() && Self::charges_in(&payment, &token_id) && current_timestamp >= Self::next_due(&payment) {
+                Self::process_plan(&env, &token_id, &from, &mut payment);
+                recurring_payments.set(from, payment);
+            }
+        }
+
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Processed recurring batch: From index: {:?}, To index: {:?}", start, end); // Debug print
+        senders.get(end)
+    }
+
+    // Process one sender's due plan; the keeper is paid the plan's keeper reward on success.
+    // Plans with a designated processor can only be processed by that address.
+    pub fn process_recurring_for(env: Env, keeper: Address, from: Address, token_id: Address) -> bool {
+        keeper.require_auth();
+        Self::record_activity(&env, &keeper);
+        Self::require_not_frozen(&env, &keeper);
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        if let Some(processor) = &payment.processor {
+            if *processor != keeper {
+                panic!("not the designated processor");
+            }
+        }
+        if payment.suspended {
+            panic!("recurring payment suspended");
+        }
+        if !Self::charges_in(&payment, &token_id) {
+            panic!("plan is charged in another token");
+        }
+        if env.ledger().timestamp() < Self::next_due(&payment) {
+            panic!("recurring payment not due");
+        }
+
+        // A plan charged from its prepaid balance pays the keeper from it too, as the sender
+        // has not signed; the reward is skipped if what is left does not cover it
+        let prepaid = Self::covered_by_prepaid(&env, &token_id, &from, &payment);
+        let charged = Self::process_plan(&env, &token_id, &from, &mut payment);
+        if let Some(charged) = charged {
+            // Rewards are paid in the processing token, so a Bps reward is a share of what was
+            // charged in it rather than of the plan amount, which may be in a payout token
+            let reward = match payment.keeper_reward {
+                KeeperReward::None => 0,
+                KeeperReward::Flat(amount) => amount,
+                KeeperReward::Bps(bps) => charged * bps as i128 / 10_000,
+            };
+            let rewarded = reward > 0 && if prepaid {
+                Self::try_pay_prepaid(&env, &token_id, &from, &keeper, reward)
+            } else if payment.use_deposit {
+                Self::try_pay_from(&env, &token_id, &from, true, &keeper, reward)
+            } else {
+                // The wallet charge already required the sender's authorization in this call
+                TokenClient::new(&env, &token_id).try_transfer(&from, &keeper, &reward).is_ok()
+            };
+            if rewarded {
+                Self::check_alert(&env, &token_id, &from, &keeper, reward);
+                println!("Keeper rewarded: Keeper: {:?}, From: {:?}, Reward: {:?}", keeper, from, reward); // Debug print
+            }
+        }
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        charged.is_some()
+    }
+
+    // Offer keepers a reward, paid by the sender, for processing their recurring plan
+    pub fn set_keeper_reward(env: Env, from: Address, keeper_reward: KeeperReward) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        match keeper_reward {
+            KeeperReward::Flat(amount) if amount < 0 => panic!("reward must not be negative"),
+            KeeperReward::Bps(bps) if bps > 10_000 => panic!("reward must not exceed 10000 bps"),
+            _ => {}
+        }
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        payment.keeper_reward = keeper_reward.clone();
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Keeper reward set: From: {:?}, Reward: {:?}", from, keeper_reward); // Debug print
+    }
+
+    // Restrict processing of the sender's plan to `processor`, or open it to anyone with None.
+    // Plans with a processor are skipped by the permissionless batch processors.
+    pub fn set_recurring_processor(env: Env, from: Address, processor: Option<Address>) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        payment.processor = processor.clone();
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Recurring processor set: From: {:?}, Processor: {:?}", from, processor); // Debug print
+    }
+
+    // Switch the sender's recurring plan to a calendar or fixed-interval schedule
+    pub fn set_recurring_schedule(env: Env, from: Address, schedule: Schedule) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        match schedule {
+            Schedule::EverySeconds(0) => panic!("interval must be positive"),
+            Schedule::Monthly(day_of_month) if !(1..=31).contains(&day_of_month) => panic!("invalid day of month"),
+            Schedule::Weekly(weekday) if weekday > 6 => panic!("invalid weekday"),
+            _ => {}
+        }
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        payment.schedule = schedule.clone();
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Recurring schedule set: From: {:?}, Schedule: {:?}", from, schedule); // Debug print
+    }
+
+    // Resume a recurring plan that was suspended after repeated failures
+    pub fn reactivate_plan(env: Env, from: Address) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        if payment.suspended {
+            Self::update_stats(&env, |stats| stats.active_plans += 1);
+        }
+        payment.suspended = false;
+        payment.failed_attempts = 0;
+        payment.retry_at = 0;
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Recurring payment reactivated: From: {:?}", from); // Debug print
+    }
+
+    // Allow a delegated address to spend up to `limit` of the owner's tokens until `expiry`.
+    // The owner must also approve this contract on the token for the delegated amount,
+    // since the contract moves the funds with `transfer_from`.
+    pub fn authorize_spender(env: Env, owner: Address, spender: Address, token_id: Address, limit: i128, expiry: u64) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if limit <= 0 {
+            panic!("limit must be positive");
+        }
+        Self::set_spender_allowance(&env, &owner, &spender, &SpenderAllowance {
+            token_id: token_id.clone(),
+            limit,
+            spent: 0,
+            expiry,
+        });
+        println!("Spender authorized: Owner: {:?}, Spender: {:?}, Token ID: {:?}, Limit: {:?}, Expiry: {:?}", owner, spender, token_id, limit, expiry); // Debug print
+    }
+
+    // Remove a delegated spender
+    pub fn revoke_spender(env: Env, owner: Address, spender: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        let key = (symbol_short!("spender"), owner.clone(), spender.clone());
+        env.storage().persistent().remove(&key);
+        println!("Spender revoked: Owner: {:?}, Spender: {:?}", owner, spender); // Debug print
+    }
+
+    // Transfer from the owner's account by a delegated spender, within its limit
+    pub fn transfer_on_behalf(env: Env, owner: Address, spender: Address, to: Address, amount: i128, memo: Memo) -> bool {
+        spender.require_auth();
+        Self::record_activity(&env, &spender);
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &owner);
+        Self::require_not_frozen(&env, &to);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        let mut allowance = Self::get_spender_allowance(&env, &owner, &spender)
+            .unwrap_or_else(|| panic!("spender not authorized"));
+        if env.ledger().timestamp() > allowance.expiry {
+            panic!("spender authorization expired");
+        }
+        if allowance.spent + amount > allowance.limit {
+            panic!("spender limit exceeded");
+        }
+
+        Self::require_no_confirmation(&env, &owner, &allowance.token_id, amount);
+        let token = TokenClient::new(&env, &allowance.token_id);
+        token.transfer_from(&env.current_contract_address(), &owner, &to, &amount);
+        Self::check_alert(&env, &allowance.token_id, &owner, &to, amount);
+
+        allowance.spent += amount;
+        Self::set_spender_allowance(&env, &owner, &spender, &allowance);
+
+        // Store payment record in the owner's history
+        Self::record_payment(&env, &allowance.token_id, Payment {
+            from: owner.clone(),
+            to: to.clone(),
+            amount,
+            message: memo,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+
+        println!("Delegated transfer: Owner: {:?}, Spender: {:?}, To: {:?}, Amount: {:?}", owner, spender, to, amount); // Debug print
+        true
+    }
+
+    // Escrow funds behind a secret; whoever presents the preimage of `secret_hash` can claim them
+    pub fn create_payment_link(env: Env, from: Address, token_id: Address, amount: i128, secret_hash: BytesN<32>, expiry: u64) -> u64 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_no_confirmation(&env, &from, &token_id, amount);
+        Self::fund_escrow(&env, &token_id, &from, amount);
+        Self::check_alert(&env, &token_id, &from, &env.current_contract_address(), amount);
+
+        let (payout_token, min_payout_bps) = Self::escrow_payout(&env, &from, &token_id);
+        let link_id = Self::next_id(&env, symbol_short!("link_id"));
+        Self::set_payment_link(&env, link_id, &PaymentLink {
+            from: from.clone(),
+            token_id,
+            amount,
+            secret_hash,
+            expiry,
+            claimed: false,
+            payout_token,
+            min_payout_bps,
+        });
+        Self::update_stats(&env, |stats| stats.open_escrows += 1);
+        println!("Payment link created: ID: {:?}, From: {:?}, Amount: {:?}, Expiry: {:?}", link_id, from, amount, expiry); // Debug print
+        link_id
+    }
+
+    // Pay out a payment link to `recipient` if the secret matches
+    pub fn claim_link(env: Env, link_id: u64, secret_preimage: Bytes, recipient: Address) -> bool {
+        let mut link = Self::get_payment_link(&env, link_id);
+        Self::require_not_frozen(&env, &recipient);
+        if link.claimed {
+            panic!("link already claimed");
+        }
+        if env.ledger().timestamp() > link.expiry {
+            panic!("link expired");
+        }
+        let hash: BytesN<32> = env.crypto().sha256(&secret_preimage).into();
+        if hash != link.secret_hash {
+            panic!("invalid secret");
+        }
+
+        link.claimed = true;
+        Self::update_reputation(&env, &link.from, |score| score.completed_escrows += 1);
+        Self::set_payment_link(&env, link_id, &link);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let (paid_token, paid) = Self::release_escrow(&env, &link.token_id, &recipient, link.amount, &link.payout_token, link.min_payout_bps);
+
+        // Store payment record
+        Self::record_payment(&env, &paid_token, Payment {
+            from: link.from.clone(),
+            to: recipient.clone(),
+            amount: paid,
+            message: Memo::None,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+
+        println!("Payment link claimed: ID: {:?}, Recipient: {:?}, Amount: {:?}", link_id, recipient, link.amount); // Debug print
+        true
+    }
+
+    // Return the escrowed funds of an expired, unclaimed link to its creator
+    pub fn reclaim_link(env: Env, link_id: u64) {
+        let mut link = Self::get_payment_link(&env, link_id);
+        link.from.require_auth();
+        Self::record_activity(&env, &link.from);
+        Self::require_not_frozen(&env, &link.from);
+        if link.claimed {
+            panic!("link already claimed");
+        }
+        if env.ledger().timestamp() <= link.expiry {
+            panic!("link not expired");
+        }
+
+        link.claimed = true;
+        Self::set_payment_link(&env, link_id, &link);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let token = TokenClient::new(&env, &link.token_id);
+        token.transfer(&env.current_contract_address(), &link.from, &link.amount);
+        println!("Payment link reclaimed: ID: {:?}, From: {:?}, Amount: {:?}", link_id, link.from, link.amount); // Debug print
+    }
+
+    // Lock funds for `receiver` until `timelock`; released by the preimage of `hashlock`
+    pub fn create_htlc(env: Env, sender: Address, receiver: Address, token_id: Address, amount: i128, hashlock: BytesN<32>, timelock: u64) -> u64 {
+        sender.require_auth();
+        Self::record_activity(&env, &sender);
+        Self::require_not_frozen(&env, &sender);
+        Self::require_not_frozen(&env, &receiver);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        if timelock <= env.ledger().timestamp() {
+            panic!("timelock must be in the future");
+        }
+        Self::require_no_confirmation(&env, &sender, &token_id, amount);
+        Self::fund_escrow(&env, &token_id, &sender, amount);
+        Self::check_alert(&env, &token_id, &sender, &receiver, amount);
+
+        let (payout_token, min_payout_bps) = Self::escrow_payout(&env, &sender, &token_id);
+        let htlc_id = Self::next_id(&env, symbol_short!("htlc_id"));
+        Self::set_htlc(&env, htlc_id, &Htlc {
+            sender: sender.clone(),
+            receiver: receiver.clone(),
+            token_id,
+            amount,
+            hashlock,
+            timelock,
+            preimage: None,
+            refunded: false,
+            payout_token,
+            min_payout_bps,
+        });
+        Self::update_stats(&env, |stats| stats.open_escrows += 1);
+        println!("HTLC created: ID: {:?}, Sender: {:?}, Receiver: {:?}, Amount: {:?}, Timelock: {:?}", htlc_id, sender, receiver, amount, timelock); // Debug print
+        htlc_id
+    }
+
+    // Release an HTLC to its receiver by revealing the preimage before the timelock
+    pub fn withdraw_htlc(env: Env, htlc_id: u64, preimage: Bytes) -> bool {
+        let mut htlc = Self::get_htlc(env.clone(), htlc_id);
+        Self::require_not_frozen(&env, &htlc.receiver);
+        if htlc.preimage.is_some() || htlc.refunded {
+            panic!("htlc already settled");
+        }
+        if env.ledger().timestamp() >= htlc.timelock {
+            panic!("htlc expired");
+        }
+        let hash: BytesN<32> = env.crypto().sha256(&preimage).into();
+        if hash != htlc.hashlock {
+            panic!("invalid preimage");
+        }
+
+        // The revealed preimage stays readable so the counterparty can settle on the other chain
+        htlc.preimage = Some(preimage.clone());
+        Self::update_reputation(&env, &htlc.sender, |score| score.completed_escrows += 1);
+        Self::update_reputation(&env, &htlc.receiver, |score| score.completed_escrows += 1);
+        Self::set_htlc(&env, htlc_id, &htlc);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+        env.events().publish((symbol_short!("htlc_wd"), htlc_id), preimage);
+
+        let (paid_token, paid) = Self::release_escrow(&env, &htlc.token_id, &htlc.receiver, htlc.amount, &htlc.payout_token, htlc.min_payout_bps);
+
+        // Store payment record
+        Self::record_payment(&env, &paid_token, Payment {
+            from: htlc.sender.clone(),
+            to: htlc.receiver.clone(),
+            amount: paid,
+            message: Memo::None,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+
+        println!("HTLC withdrawn: ID: {:?}, Receiver: {:?}, Amount: {:?}", htlc_id, htlc.receiver, htlc.amount); // Debug print
+        true
+    }
+
+    // Return an HTLC to its sender once the timelock has passed
+    pub fn refund_htlc(env: Env, htlc_id: u64) {
+        let mut htlc = Self::get_htlc(env.clone(), htlc_id);
+        Self::require_not_frozen(&env, &htlc.sender);
+        if htlc.preimage.is_some() || htlc.refunded {
+            panic!("htlc already settled");
+        }
+        if env.ledger().timestamp() < htlc.timelock {
+            panic!("htlc not expired");
+        }
+
+        htlc.refunded = true;
+        Self::set_htlc(&env, htlc_id, &htlc);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let token = TokenClient::new(&env, &htlc.token_id);
+        token.transfer(&env.current_contract_address(), &htlc.sender, &htlc.amount);
+        println!("HTLC refunded: ID: {:?}, Sender: {:?}, Amount: {:?}", htlc_id, htlc.sender, htlc.amount); // Debug print
+    }
+
+    // View an HTLC, including the preimage once revealed
+    pub fn get_htlc(env: Env, htlc_id: u64) -> Htlc {
+        let key = (symbol_short!("htlc"), htlc_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("htlc not found"))
+    }
+
+    // Start a fundraising campaign collecting `token_id` towards `goal` until `deadline`
+    pub fn create_campaign(env: Env, owner: Address, token_id: Address, goal: i128, deadline: u64, description: String) -> u64 {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if goal <= 0 {
+            panic!("goal must be positive");
+        }
+        let campaign_id = Self::next_id(&env, symbol_short!("camp_id"));
+        Self::set_campaign(&env, campaign_id, &Campaign {
+            owner: owner.clone(),
+            token_id,
+            goal,
+            deadline,
+            description,
+            raised: 0,
+            withdrawn: false,
+        });
+        println!("Campaign created: ID: {:?}, Owner: {:?}, Goal: {:?}, Deadline: {:?}", campaign_id, owner, goal, deadline); // Debug print
+        campaign_id
+    }
+
+    // Contribute to a campaign; funds are held by the contract until withdrawn or refunded
+    pub fn donate(env: Env, campaign_id: u64, from: Address, amount: i128, message: String) -> bool {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        let mut campaign = Self::get_campaign(env.clone(), campaign_id);
+        if env.ledger().timestamp() > campaign.deadline {
+            panic!("campaign ended");
+        }
+        // Funds donated after the owner withdrew could be neither withdrawn nor refunded
+        if campaign.withdrawn {
+            panic!("campaign already withdrawn");
+        }
+
+        Self::require_no_confirmation(&env, &from, &campaign.token_id, amount);
+        let token = TokenClient::new(&env, &campaign.token_id);
+        token.transfer(&from, &env.current_contract_address(), &amount);
+        Self::check_alert(&env, &campaign.token_id, &from, &campaign.owner, amount);
+
+        campaign.raised += amount;
+        Self::set_campaign(&env, campaign_id, &campaign);
+
+        let donated_key = (symbol_short!("donated"), campaign_id, from.clone());
+        let donated: i128 = env.storage().persistent().get(&donated_key).unwrap_or(0);
+        env.storage().persistent().set(&donated_key, &(donated + amount));
+
+        let mut donations = Self::get_donations(env.clone(), campaign_id);
+        donations.push_back(Donation {
+            from: from.clone(),
+            amount,
+            message: message.clone(),
+        });
+        env.storage().persistent().set(&(symbol_short!("donations"), campaign_id), &donations);
+
+        // Store payment record
+        Self::record_payment(&env, &campaign.token_id, Payment {
+            from: from.clone(),
+            to: campaign.owner.clone(),
+            amount,
+            message: Memo::Text(message),
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+
+        println!("Donation received: Campaign: {:?}, From: {:?}, Amount: {:?}, Raised: {:?}", campaign_id, from, amount, campaign.raised); // Debug print
+        true
+    }
+
+    // Pay out everything raised once the goal has been reached
+    pub fn withdraw_campaign(env: Env, campaign_id: u64) -> i128 {
+        let mut campaign = Self::get_campaign(env.clone(), campaign_id);
+        campaign.owner.require_auth();
+        Self::record_activity(&env, &campaign.owner);
+        Self::require_not_frozen(&env, &campaign.owner);
+        if campaign.withdrawn {
+            panic!("campaign already withdrawn");
+        }
+        if campaign.raised < campaign.goal {
+            panic!("goal not reached");
+        }
+
+        campaign.withdrawn = true;
+        Self::set_campaign(&env, campaign_id, &campaign);
+
+        let token = TokenClient::new(&env, &campaign.token_id);
+        token.transfer(&env.current_contract_address(), &campaign.owner, &campaign.raised);
+        println!("Campaign withdrawn: ID: {:?}, Owner: {:?}, Amount: {:?}", campaign_id, campaign.owner, campaign.raised); // Debug print
+        campaign.raised
+    }
+
+    // Return a donor's contributions when the campaign missed its goal by the deadline
+    pub fn refund_donation(env: Env, campaign_id: u64, donor: Address) -> i128 {
+        donor.require_auth();
+        Self::record_activity(&env, &donor);
+        Self::require_not_frozen(&env, &donor);
+        let mut campaign = Self::get_campaign(env.clone(), campaign_id);
+        if env.ledger().timestamp() <= campaign.deadline {
+            panic!("campaign still running");
+        }
+        if campaign.raised >= campaign.goal {
+            panic!("goal reached");
+        }
+
+        let donated_key = (symbol_short!("donated"), campaign_id, donor.clone());
+        let donated: i128 = env.storage().persistent().get(&donated_key).unwrap_or(0);
+        if donated == 0 {
+            panic!("nothing to refund");
+        }
+        env.storage().persistent().remove(&donated_key);
+
+        campaign.raised -= donated;
+        Self::set_campaign(&env, campaign_id, &campaign);
+
+        let token = TokenClient::new(&env, &campaign.token_id);
+        token.transfer(&env.current_contract_address(), &donor, &donated);
+        println!("Donation refunded: Campaign: {:?}, Donor: {:?}, Amount: {:?}", campaign_id, donor, donated); // Debug print
+        donated
+    }
+
+    // View a campaign
+    pub fn get_campaign(env: Env, campaign_id: u64) -> Campaign {
+        let key = (symbol_short!("campaign"), campaign_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("campaign not found"))
+    }
+
+    // View the donations and donor messages of a campaign
+    pub fn get_donations(env: Env, campaign_id: u64) -> Vec<Donation> {
+        let key = (symbol_short!("donations"), campaign_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(&env))
+    }
+
+    // Create a payroll with one recurring plan per employee, all paid in `token_id`
+    pub fn create_payroll(env: Env, employer: Address, token_id: Address, employees: Vec<(Address, i128)>, interval: u64) -> u64 {
+        employer.require_auth();
+        Self::record_activity(&env, &employer);
+        if employees.is_empty() {
+            panic!("payroll has no employees");
+        }
+
+        let mut plans = Vec::new(&env);
+        for (employee, amount) in employees.iter() {
+            if amount <= 0 {
+                panic!("amount must be positive");
+            }
+            plans.push_back(RecurringPayment {
+                to: employee,
+                amount,
+                schedule: Schedule::EverySeconds(interval),
+                message: String::from_str(&env, "Payroll"),
+                last_payment: env.ledger().timestamp(),
+                payout_token: None,
+                max_deposit: 0,
+                use_deposit: false,
+                pinned_token: None,
+                failed_attempts: 0,
+                retry_at: 0,
+                suspended: false,
+                keeper_reward: KeeperReward::None,
+                processor: None,
+                extra_legs: Vec::new(&env),
+            });
+        }
+
+        let payroll_id = Self::next_id(&env, symbol_short!("payrl_id"));
+        Self::set_payroll(&env, payroll_id, &Payroll {
+            employer: employer.clone(),
+            token_id,
+            plans,
+        });
+        println!("Payroll created: ID: {:?}, Employer: {:?}, Employees: {:?}, Interval: {:?}", payroll_id, employer, employees.len(), interval); // Debug print
+        payroll_id
+    }
+
+    // Pay every employee whose plan is due, tagging the resulting payments with one batch ID
+    pub fn run_payroll(env: Env, payroll_id: u64) -> u64 {
+        let mut payroll = Self::get_payroll(env.clone(), payroll_id);
+        payroll.employer.require_auth();
+        Self::record_activity(&env, &payroll.employer);
+        Self::require_not_frozen(&env, &payroll.employer);
+        let current_timestamp = env.ledger().timestamp();
+        let token = TokenClient::new(&env, &payroll.token_id);
+        let batch_id = Self::next_id(&env, symbol_short!("batch_id"));
+
+        let mut paid = 0u32;
+        for (i, mut plan) in payroll.plans.iter().enumerate() {
+            // Frozen employees are skipped rather than blocking the whole run
+            if current_timestamp < Self::next_due(&plan) || Self::is_frozen(env.clone(), plan.to.clone()) {
+                continue;
+            }
+            token.transfer(&payroll.employer, &plan.to, &plan.amount);
+            Self::check_alert(&env, &payroll.token_id, &payroll.employer, &plan.to, plan.amount);
+
+            plan.last_payment = current_timestamp;
+            payroll.plans.set(i as u32, plan.clone());
+            Self::update_reputation(&env, &payroll.employer, |score| score.on_time_recurring += 1);
+
+            // Store payment record
+            Self::record_payment(&env, &payroll.token_id, Payment {
+                from: payroll.employer.clone(),
+                to: plan.to.clone(),
+                amount: plan.amount,
+                message: Memo::Text(plan.message.clone()),
+                batch_id: Some(batch_id),
+                metadata: None,
+                attachments: Vec::new(&env),
+            });
+            paid += 1;
+        }
+        if paid == 0 {
+            panic!("no payroll payments due");
+        }
+
+        Self::set_payroll(&env, payroll_id, &payroll);
+        println!("Payroll run: ID: {:?}, Batch: {:?}, Payments: {:?}", payroll_id, batch_id, paid); // Debug print
+        batch_id
+    }
+
+    // View a payroll and its per-employee plans
+    pub fn get_payroll(env: Env, payroll_id: u64) -> Payroll {
+        let key = (symbol_short!("payroll"), payroll_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("payroll not found"))
+    }
+
+    // Set the contract admin; can only be done once
+    pub fn initialize(env: Env, admin: Address) {
+        if env.storage().persistent().has(&symbol_short!("admin")) {
+            panic!("already initialized");
+        }
+        admin.require_auth();
+        env.storage().persistent().set(&symbol_short!("admin"), &admin);
+        println!("Contract initialized: Admin: {:?}", admin); // Debug print
+    }
+
+    // Limit each sender to `max_transfers` transfers per `window_ledgers` ledgers (admin only)
+    pub fn set_rate_limit(env: Env, max_transfers: u32, window_ledgers: u32) {
+        Self::require_admin(&env);
+        if window_ledgers == 0 {
+            panic!("window must be positive");
+        }
+        env.storage().persistent().set(&symbol_short!("ratelimit"), &RateLimit {
+            max_transfers,
+            window_ledgers,
+        });
+        println!("Rate limit set: Max transfers: {:?}, Window ledgers: {:?}", max_transfers, window_ledgers); // Debug print
+    }
+
+    // Remove the rate limit (admin only)
+    pub fn clear_rate_limit(env: Env) {
+        Self::require_admin(&env);
+        env.storage().persistent().remove(&symbol_short!("ratelimit"));
+    }
+
+    // View how much of the current rate limit window an address has used
+    pub fn get_rate_limit_status(env: Env, address: Address) -> Option<RateLimitStatus> {
+        let limit: RateLimit = env.storage().persistent().get(&symbol_short!("ratelimit"))?;
+        let (window_start, used) = Self::get_rate_window(&env, &address, &limit);
+        Some(RateLimitStatus {
+            used,
+            max_transfers: limit.max_transfers,
+            window_start,
+            window_ledgers: limit.window_ledgers,
+        })
+    }
+
+    // Move tokens into the owner's internal balance held by this contract
+    pub fn deposit(env: Env, owner: Address, token_id: Address, amount: i128) -> i128 {
+        owner.require_auth();
+        Self::require_not_frozen(&env, &owner);
+        Self::record_activity(&env, &owner);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&owner, &env.current_contract_address(), &amount);
+        let balance = Self::get_deposit(env.clone(), owner.clone(), token_id.clone()) + amount;
+        Self::set_deposit(&env, &owner, &token_id, balance);
+        println!("Deposit: Owner: {:?}, Token ID: {:?}, Amount: {:?}, Balance: {:?}", owner, token_id, amount, balance); // Debug print
+        balance
+    }
+
+    // Move tokens from the owner's internal balance back to their wallet
+    pub fn withdraw(env: Env, owner: Address, token_id: Address, amount: i128) -> i128 {
+        owner.require_auth();
+        Self::require_not_frozen(&env, &owner);
+        Self::record_activity(&env, &owner);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        let balance = Self::debit_deposit(&env, &owner, &token_id, amount);
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&env.current_contract_address(), &owner, &amount);
+        println!("Withdraw: Owner: {:?}, Token ID: {:?}, Amount: {:?}, Balance: {:?}", owner, token_id, amount, balance); // Debug print
+        balance
+    }
+
+    // View an owner's internal balance of a token
+    pub fn get_deposit(env: Env, owner: Address, token_id: Address) -> i128 {
+        let key = (symbol_short!("deposit"), owner, token_id);
+        env.storage().persistent().get(&key).unwrap_or(0)
+    }
+
+    // Fund the owner's payment links, HTLCs, gifts, conditional payments, airdrops and vouchers
+    // from their internal balance instead of their wallet. Refunds still go to the wallet.
+    pub fn set_escrow_use_deposit(env: Env, owner: Address, use_deposit: bool) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().set(&(symbol_short!("esc_dep"), owner.clone()), &use_deposit);
+        println!("Escrow funding set: Owner: {:?}, Use deposit: {:?}", owner, use_deposit); // Debug print
+    }
+
+    // Whether the owner's escrows are funded from their internal balance
+    pub fn get_escrow_use_deposit(env: Env, owner: Address) -> bool {
+        env.storage().persistent().get(&(symbol_short!("esc_dep"), owner)).unwrap_or(false)
+    }
+
+    // Pay out the owner's escrows of `token_id` created from now on in `payout_token`, swapped
+    // through the liquidity pool when claimed. A claim fails unless the swap returns at least
+    // `min_payout_bps` of the escrowed amount (in payout token units); refunds are not converted.
+    pub fn set_escrow_payout_token(env: Env, owner: Address, token_id: Address, payout_token: Address, min_payout_bps: u32) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if payout_token == token_id {
+            panic!("payout token must differ");
+        }
+        if min_payout_bps == 0 {
+            panic!("min payout must be positive");
+        }
+        let key = (symbol_short!("esc_pay"), owner.clone(), token_id.clone());
+        env.storage().persistent().set(&key, &(payout_token.clone(), min_payout_bps));
+        println!("Escrow payout token set: Owner: {:?}, Token ID: {:?}, Payout token: {:?}, Min payout bps: {:?}", owner, token_id, payout_token, min_payout_bps); // Debug print
+    }
+
+    // Pay out the owner's future escrows of `token_id` in that token again
+    pub fn clear_escrow_payout_token(env: Env, owner: Address, token_id: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().remove(&(symbol_short!("esc_pay"), owner, token_id));
+    }
+
+    // The owner's (payout_token, min_payout_bps) for escrows of `token_id`, if set
+    pub fn get_escrow_payout_token(env: Env, owner: Address, token_id: Address) -> Option<(Address, u32)> {
+        env.storage().persistent().get(&(symbol_short!("esc_pay"), owner, token_id))
+    }
+
+    // Transfer from the sender's internal balance instead of their wallet
+    pub fn transfer_from_deposit(env: Env, token_id: Address, from: Address, to: Address, amount: i128, memo: Memo) -> bool {
+        from.require_auth();
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &from);
+        Self::require_not_frozen(&env, &to);
+        Self::record_activity(&env, &from);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::consume_rate_limit(&env, &from, 1);
+        Self::require_no_confirmation(&env, &from, &token_id, amount);
+        Self::debit_deposit(&env, &from, &token_id, amount);
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&env.current_contract_address(), &to, &amount);
+        Self::check_alert(&env, &token_id, &from, &to, amount);
+
+        // Store payment record
+        Self::record_payment(&env, &token_id, Payment {
+            from: from.clone(),
+            to: to.clone(),
+            amount,
+            message: memo,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Transfer from deposit: From: {:?}, To: {:?}, Amount: {:?}", from, to, amount); // Debug print
+        true
+    }
+
+    // Fund the sender's recurring plan from their internal balance, so anyone can process it
+    pub fn set_recurring_use_deposit(env: Env, from: Address, deposit_token: Option<Address>) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        if deposit_token.is_some() && payment.payout_token.is_some() {
+            panic!("plan pays out in another token");
+        }
+        if deposit_token.is_some() && !payment.extra_legs.is_empty() {
+            panic!("plan pays a multi-asset bundle");
+        }
+        // A prepaid balance keeps the plan pinned to its token
+        let prepaid_token = payment.pinned_token.clone().filter(|pinned_token| Self::get_prepaid(env.clone(), from.clone(), pinned_token.clone()) > 0);
+        if deposit_token.is_some() && prepaid_token.is_some() && deposit_token != prepaid_token {
+            panic!("plan is prepaid in another token");
+        }
+        payment.use_deposit = deposit_token.is_some();
+        payment.pinned_token = deposit_token.clone().or(prepaid_token);
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Recurring deposit funding set: From: {:?}, Deposit token: {:?}", from, deposit_token); // Debug print
+    }
+
+    // Name beneficiaries (shares in basis points) for the owner's deposits if they go inactive
+    pub fn configure_inheritance(env: Env, owner: Address, beneficiaries: Vec<(Address, u32)>, inactivity_secs: u64) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if beneficiaries.is_empty() || Self::total_bps(&beneficiaries) != Some(10_000) {
+            panic!("beneficiary shares must sum to 10000 bps");
+        }
+        if inactivity_secs == 0 {
+            panic!("inactivity period must be positive");
+        }
+        Self::set_inheritance_plan(&env, &owner, &InheritancePlan {
+            beneficiaries,
+            inactivity_secs,
+            last_active: env.ledger().timestamp(),
+        });
+        println!("Inheritance configured: Owner: {:?}, Inactivity secs: {:?}", owner, inactivity_secs); // Debug print
+    }
+
+    // Remove the owner's inheritance plan
+    pub fn cancel_inheritance(env: Env, owner: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().remove(&(symbol_short!("inherit"), owner.clone()));
+        println!("Inheritance cancelled: Owner: {:?}", owner); // Debug print
+    }
+
+    // Split an inactive owner's deposit of `token_id` between their beneficiaries; callable by anyone
+    pub fn trigger_inheritance(env: Env, owner: Address, token_id: Address) -> i128 {
+        let plan = Self::get_inheritance_plan(env.clone(), owner.clone()).unwrap_or_else(|| panic!("no inheritance plan"));
+        Self::require_not_frozen(&env, &owner);
+        if env.ledger().timestamp() < plan.last_active + plan.inactivity_secs {
+            panic!("owner still active");
+        }
+        let total = Self::get_deposit(env.clone(), owner.clone(), token_id.clone());
+        if total == 0 {
+            panic!("nothing to distribute");
+        }
+        Self::set_deposit(&env, &owner, &token_id, 0);
+
+        let token = TokenClient::new(&env, &token_id);
+        let mut remaining = total;
+        let last = plan.beneficiaries.len() - 1;
+        for (i, (beneficiary, bps)) in plan.beneficiaries.iter().enumerate() {
+            // The last beneficiary also receives any rounding remainder
+            let share = if i as u32 == last { remaining } else { total * bps as i128 / 10_000 };
+            remaining -= share;
+            if share > 0 {
+                Self::require_not_frozen(&env, &beneficiary);
+                token.transfer(&env.current_contract_address(), &beneficiary, &share);
+                Self::record_payment(&env, &token_id, Payment {
+                    from: owner.clone(),
+                    to: beneficiary.clone(),
+                    amount: share,
+                    message: Memo::Text(String::from_str(&env, "Inheritance")),
+                    batch_id: None,
+                    metadata: None,
+                    attachments: Vec::new(&env),
+                });
+            }
+        }
+        println!("Inheritance triggered: Owner: {:?}, Token ID: {:?}, Amount: {:?}", owner, token_id, total); // Debug print
+        total
+    }
+
+    // View the owner's inheritance plan
+    pub fn get_inheritance_plan(env: Env, owner: Address) -> Option<InheritancePlan> {
+        env.storage().persistent().get(&(symbol_short!("inherit"), owner))
+    }
+
+    // Register a temporary key that may perform `allowed_ops` ("transfer", "send_msg") for the
+    // owner until `expiry`, each operation moving at most `max_amount`
+    pub fn create_session_key(env: Env, owner: Address, session_addr: Address, allowed_ops: Vec<Symbol>, expiry: u64, max_amount: i128) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if expiry <= env.ledger().timestamp() {
+            panic!("expiry must be in the future");
+        }
+        let key = (symbol_short!("session"), owner.clone(), session_addr.clone());
+        env.storage().persistent().set(&key, &SessionKey {
+            allowed_ops,
+            expiry,
+            max_amount,
+        });
+        println!("Session key created: Owner: {:?}, Session: {:?}, Expiry: {:?}, Max amount: {:?}", owner, session_addr, expiry, max_amount); // Debug print
+    }
+
+    // Remove a session key
+    pub fn revoke_session_key(env: Env, owner: Address, session_addr: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        let key = (symbol_short!("session"), owner.clone(), session_addr.clone());
+        env.storage().persistent().remove(&key);
+        println!("Session key revoked: Owner: {:?}, Session: {:?}", owner, session_addr); // Debug print
+    }
+
+    // Transfer from the owner's internal deposit, signed by a session key allowed to "transfer"
+    pub fn session_transfer(env: Env, session_addr: Address, owner: Address, token_id: Address, to: Address, amount: i128, memo: Memo) -> bool {
+        Self::require_session(&env, &owner, &session_addr, symbol_short!("transfer"), amount);
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &owner);
+        Self::require_not_frozen(&env, &to);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::consume_rate_limit(&env, &owner, 1);
+        Self::require_no_confirmation(&env, &owner, &token_id, amount);
+        Self::debit_deposit(&env, &owner, &token_id, amount);
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&env.current_contract_address(), &to, &amount);
+        Self::check_alert(&env, &token_id, &owner, &to, amount);
+
+        // Store payment record
+        Self::record_payment(&env, &token_id, Payment {
+            from: owner.clone(),
+            to: to.clone(),
+            amount,
+            message: memo,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Session transfer: Owner: {:?}, Session: {:?}, To: {:?}, Amount: {:?}", owner, session_addr, to, amount); // Debug print
+        true
+    }
+
+    // Ask `payer` for `amount` of `token_id`
+    pub fn request_payment(env: Env, requester: Address, payer: Address, token_id: Address, amount: i128, message: String, options: Option<RequestOptions>) -> u64 {
+        Self::create_payment_request(&env, PaymentRequest {
+            requester,
+            payer,
+            token_id,
+            amount,
+            paid: 0,
+            message,
+            fulfilled: false,
+            due_date: options.as_ref().and_then(|options| options.due_date),
+            grace_period: options.as_ref().map_or(0, |options| options.grace_period),
+            late_fee_bps: options.as_ref().map_or(0, |options| options.late_fee_bps),
+            late_fees_paid: 0,
+            usd_denominated: false,
+            metadata: options.and_then(|options| options.metadata),
+        })
+    }
+
+    // Ask `payer` for a USD amount (in the oracle's decimals), settled in `settlement_token`
+    // at the oracle rate when paid
+    pub fn request_payment_usd(env: Env, requester: Address, payer: Address, usd_amount: i128, settlement_token: Address) -> u64 {
+        Self::create_payment_request(&env, PaymentRequest {
+            requester,
+            payer,
+            token_id: settlement_token,
+            amount: usd_amount,
+            paid: 0,
+            message: String::from_str(&env, ""),
+            fulfilled: false,
+            due_date: None,
+            grace_period: 0,
+            late_fee_bps: 0,
+            late_fees_paid: 0,
+            usd_denominated: true,
+            metadata: None,
+        })
+    }
+
+    // Set the price oracle and the maximum age of a usable price in seconds (admin only)
+    pub fn set_oracle(env: Env, oracle: Address, max_staleness: u64) {
+        Self::require_admin(&env);
+        env.storage().persistent().set(&symbol_short!("oracle"), &(oracle.clone(), max_staleness));
+        println!("Oracle set: {:?}, Max staleness: {:?}", oracle, max_staleness); // Debug print
+    }
+
+    // Pay part of a payment request; the request closes once the full amount is paid
+    pub fn pay_request_partial(env: Env, payer: Address, request_id: u64, amount: i128) -> i128 {
+        payer.require_auth();
+        Self::record_activity(&env, &payer);
+        let mut request = Self::get_payment_request(env.clone(), request_id);
+        Self::require_not_frozen(&env, &payer);
+        Self::require_not_frozen(&env, &request.requester);
+        if payer != request.payer {
+            panic!("not the payer of this request");
+        }
+        if request.fulfilled {
+            panic!("request already fulfilled");
+        }
+        if amount <= 0 || amount > request.amount - request.paid {
+            panic!("invalid installment amount");
+        }
+
+        let late_fee = Self::late_fee(&env, &request, amount);
+        let settlement = if request.usd_denominated {
+            Self::usd_to_token(&env, &request.token_id, amount + late_fee)
+        } else {
+            amount + late_fee
+        };
+        Self::require_no_confirmation(&env, &payer, &request.token_id, settlement);
+        let token = TokenClient::new(&env, &request.token_id);
+        token.transfer(&payer, &request.requester, &settlement);
+        Self::check_alert(&env, &request.token_id, &payer, &request.requester, settlement);
+        request.late_fees_paid += late_fee;
+        if late_fee > 0 {
+            Self::update_reputation(&env, &payer, |score| score.late_payments += 1);
+        }
+
+        // Store payment record
+        Self::record_payment(&env, &request.token_id, Payment {
+            from: payer.clone(),
+            to: request.requester.clone(),
+            amount: settlement,
+            message: Memo::Text(request.message.clone()),
+            batch_id: None,
+            metadata: request.metadata.clone(),
+            attachments: Vec::new(&env),
+        });
+
+        request.paid += amount;
+        if request.paid == request.amount {
+            request.fulfilled = true;
+            Self::update_reputation(&env, &payer, |score| score.fulfilled_requests += 1);
+            env.events().publish((symbol_short!("fulfilled"), request_id), request.amount);
+        }
+        Self::set_payment_request(&env, request_id, &request);
+        println!("Request installment paid: ID: {:?}, Payer: {:?}, Amount: {:?}, Paid: {:?}", request_id, payer, amount, request.paid); // Debug print
+        request.amount - request.paid
+    }
+
+    // Amount needed to settle a request right now, including any late fee,
+    // in the request's unit (USD for requests made with request_payment_usd)
+    pub fn get_amount_due(env: Env, request_id: u64) -> i128 {
+        let request = Self::get_payment_request(env.clone(), request_id);
+        let outstanding = request.amount - request.paid;
+        outstanding + Self::late_fee(&env, &request, outstanding)
+    }
+
+    // View a payment request
+    pub fn get_payment_request(env: Env, request_id: u64) -> PaymentRequest {
+        let key = (symbol_short!("request"), request_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("request not found"))
+    }
+
+    // Counters of an address's track record in this contract
+    pub fn get_reputation(env: Env, address: Address) -> ReputationScore {
+        env.storage().persistent().get(&(symbol_short!("rep"), address)).unwrap_or_default()
+    }
+
+    // Send one message to many recipients; the body is stored once and referenced from each inbox
+    pub fn broadcast_message(env: Env, from: Address, recipients: Vec<Address>, text: String, attachments: Vec<BytesN<32>>) -> u64 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_within_limit(&env, recipients.len(), |limits| limits.max_recipients_per_batch, Error::TooManyRecipients);
+        Self::require_within_limit(&env, text.len(), |limits| limits.max_message_len, Error::MessageTooLong);
+        if recipients.is_empty() {
+            panic!("no recipients");
+        }
+        Self::check_attachments(&attachments);
+        Self::consume_rate_limit(&env, &from, 1);
+
+        let msg_id = Self::next_id(&env, symbol_short!("msg_id"));
+        env.storage().persistent().set(&(symbol_short!("msg"), msg_id), &Message {
+            from: from.clone(),
+            text,
+            sent_at: env.ledger().timestamp(),
+            attachments: attachments.clone(),
+        });
+        for recipient in recipients.iter() {
+            Self::deliver_message(&env, &recipient, msg_id);
+        }
+        Self::publish_attachments(&env, msg_id, &from, &attachments);
+        println!("Message broadcast: ID: {:?}, From: {:?}, Recipients: {:?}", msg_id, from, recipients.len()); // Debug print
+        msg_id
+    }
+
+    // Send a message to one recipient; ephemeral messages live in temporary storage and expire
+    // after the configured TTL instead of paying persistent rent
+    pub fn send_message(env: Env, from: Address, to: Address, text: String, ephemeral: bool, attachments: Vec<BytesN<32>>) -> u64 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::store_message(&env, from, to, text, ephemeral, attachments)
+    }
+
+    // send_message on behalf of `owner`, signed by a session key allowed to "send_msg"
+    pub fn session_send_message(env: Env, session_addr: Address, owner: Address, to: Address, text: String, ephemeral: bool, attachments: Vec<BytesN<32>>) -> u64 {
+        Self::require_session(&env, &owner, &session_addr, symbol_short!("send_msg"), 0);
+        Self::store_message(&env, owner, to, text, ephemeral, attachments)
+    }
+
+    // Set how many ledgers ephemeral messages live (admin only)
+    pub fn set_ephemeral_ttl(env: Env, ttl_ledgers: u32) {
+        Self::require_admin(&env);
+        env.storage().persistent().set(&symbol_short!("eph_ttl"), &ttl_ledgers);
+        println!("Ephemeral TTL set: {:?}", ttl_ledgers); // Debug print
+    }
+
+    pub fn get_ephemeral_ttl(env: Env) -> u32 {
+        env.storage().persistent().get(&symbol_short!("eph_ttl")).unwrap_or(DEFAULT_EPHEMERAL_TTL)
+    }
+
+    // Messages in the owner's inbox, oldest first; expired ephemeral messages are skipped
+    pub fn get_inbox(env: Env, owner: Address) -> Vec<Message> {
+        owner.require_auth();
+        Self::deliver_due(&env, &owner);
+        let mut messages = Vec::new(&env);
+        for msg_id in Self::get_inbox_ids(&env, &owner).iter() {
+            if let Some(message) = Self::get_message(&env, msg_id) {
+                messages.push_back(message);
+            }
+        }
+        messages
+    }
+
+    // Escrow a gift for `to` that can be claimed, and its message read, from `unlock_at`
+    pub fn send_gift(env: Env, from: Address, to: Address, token_id: Address, amount: i128, unlock_at: u64, hidden_message: String) -> u64 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        Self::require_not_frozen(&env, &to);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_no_confirmation(&env, &from, &token_id, amount);
+        Self::fund_escrow(&env, &token_id, &from, amount);
+        Self::check_alert(&env, &token_id, &from, &to, amount);
+
+        let (payout_token, min_payout_bps) = Self::escrow_payout(&env, &from, &token_id);
+        let gift_id = Self::next_id(&env, symbol_short!("gift_id"));
+        Self::set_gift(&env, gift_id, &Gift {
+            from: from.clone(),
+            to: to.clone(),
+            token_id,
+            amount,
+            unlock_at,
+            hidden_message,
+            claimed: false,
+            payout_token,
+            min_payout_bps,
+        });
+        Self::update_stats(&env, |stats| stats.open_escrows += 1);
+        println!("Gift sent: ID: {:?}, From: {:?}, To: {:?}, Amount: {:?}, Unlock at: {:?}", gift_id, from, to, amount, unlock_at); // Debug print
+        gift_id
+    }
+
+    // Claim an unlocked gift, returning its message
+    pub fn claim_gift(env: Env, gift_id: u64) -> String {
+        let mut gift = Self::get_gift(&env, gift_id);
+        gift.to.require_auth();
+        Self::record_activity(&env, &gift.to);
+        Self::require_not_frozen(&env, &gift.to);
+        if gift.claimed {
+            panic!("gift already claimed");
+        }
+        if env.ledger().timestamp() < gift.unlock_at {
+            panic!("gift still locked");
+        }
+
+        gift.claimed = true;
+        Self::set_gift(&env, gift_id, &gift);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let (paid_token, paid) = Self::release_escrow(&env, &gift.token_id, &gift.to, gift.amount, &gift.payout_token, gift.min_payout_bps);
+
+        // Store payment record
+        Self::record_payment(&env, &paid_token, Payment {
+            from: gift.from.clone(),
+            to: gift.to.clone(),
+            amount: paid,
+            message: Memo::Text(gift.hidden_message.clone()),
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Gift claimed: ID: {:?}, To: {:?}, Amount: {:?}", gift_id, gift.to, gift.amount); // Debug print
+        gift.hidden_message
+    }
+
+    // The gift's message, available once it has unlocked
+    pub fn get_gift_message(env: Env, gift_id: u64) -> String {
+        let gift = Self::get_gift(&env, gift_id);
+        if env.ledger().timestamp() < gift.unlock_at {
+            panic!("gift still locked");
+        }
+        gift.hidden_message
+    }
+
+    // Publish the owner's display name, avatar hash and bio for messaging UIs
+    pub fn set_profile(env: Env, owner: Address, display_name: String, avatar_hash: BytesN<32>, bio: String) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if display_name.len() > MAX_DISPLAY_NAME_LEN || bio.len() > MAX_BIO_LEN {
+            panic!("profile field too long");
+        }
+        env.storage().persistent().set(&(symbol_short!("profile"), owner.clone()), &Profile {
+            display_name,
+            avatar_hash,
+            bio,
+        });
+        println!("Profile set: Owner: {:?}", owner); // Debug print
+    }
+
+    // View an address's profile, if it has one
+    pub fn get_profile(env: Env, address: Address) -> Option<Profile> {
+        env.storage().persistent().get(&(symbol_short!("profile"), address))
+    }
+
+    // Merchant proposes charging `subscriber` `amount` every `interval` seconds
+    pub fn propose_subscription(env: Env, merchant: Address, subscriber: Address, token_id: Address, amount: i128, interval: u64) -> u64 {
+        merchant.require_auth();
+        Self::record_activity(&env, &merchant);
+        if amount <= 0 || interval == 0 {
+            panic!("amount and interval must be positive");
+        }
+        let subscription_id = Self::next_id(&env, symbol_short!("sub_id"));
+        Self::set_subscription(&env, subscription_id, &Subscription {
+            merchant: merchant.clone(),
+            subscriber: subscriber.clone(),
+            token_id,
+            amount,
+            interval,
+            status: SubscriptionStatus::Proposed,
+            next_charge_at: 0,
+        });
+        println!("Subscription proposed: ID: {:?}, Merchant: {:?}, Subscriber: {:?}, Amount: {:?}", subscription_id, merchant, subscriber, amount); // Debug print
+        subscription_id
+    }
+
+    // Subscriber accepts; charges are drawn from their internal deposit, the first one immediately
+    pub fn accept_subscription(env: Env, subscription_id: u64) {
+        let mut subscription = Self::get_subscription(env.clone(), subscription_id);
+        subscription.subscriber.require_auth();
+        Self::record_activity(&env, &subscription.subscriber);
+        if subscription.status != SubscriptionStatus::Proposed {
+            panic!("subscription not pending");
+        }
+        subscription.status = SubscriptionStatus::Active;
+        subscription.next_charge_at = env.ledger().timestamp();
+        Self::set_subscription(&env, subscription_id, &subscription);
+        println!("Subscription accepted: ID: {:?}", subscription_id); // Debug print
+    }
+
+    // Merchant collects a due charge from the subscriber's deposit
+    pub fn charge_subscription(env: Env, subscription_id: u64) -> bool {
+        let mut subscription = Self::get_subscription(env.clone(), subscription_id);
+        subscription.merchant.require_auth();
+        Self::record_activity(&env, &subscription.merchant);
+        Self::require_not_frozen(&env, &subscription.merchant);
+        Self::require_not_frozen(&env, &subscription.subscriber);
+        if subscription.status != SubscriptionStatus::Active {
+            panic!("subscription not active");
+        }
+        if env.ledger().timestamp() < subscription.next_charge_at {
+            panic!("subscription charge not due");
+        }
+
+        Self::debit_deposit(&env, &subscription.subscriber, &subscription.token_id, subscription.amount);
+        let token = TokenClient::new(&env, &subscription.token_id);
+        token.transfer(&env.current_contract_address(), &subscription.merchant, &subscription.amount);
+        Self::check_alert(&env, &subscription.token_id, &subscription.subscriber, &subscription.merchant, subscription.amount);
+        subscription.next_charge_at += subscription.interval;
+        Self::set_subscription(&env, subscription_id, &subscription);
+
+        // Store payment record
+        Self::record_payment(&env, &subscription.token_id, Payment {
+            from: subscription.subscriber.clone(),
+            to: subscription.merchant.clone(),
+            amount: subscription.amount,
+            message: Memo::Text(String::from_str(&env, "Subscription")),
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Subscription charged: ID: {:?}, Amount: {:?}", subscription_id, subscription.amount); // Debug print
+        true
+    }
+
+    // Either party can end a subscription
+    pub fn cancel_subscription(env: Env, subscription_id: u64, caller: Address) {
+        caller.require_auth();
+        Self::record_activity(&env, &caller);
+        let mut subscription = Self::get_subscription(env.clone(), subscription_id);
+        if caller != subscription.merchant && caller != subscription.subscriber {
+            panic!("not a party to this subscription");
+        }
+        subscription.status = SubscriptionStatus::Cancelled;
+        Self::set_subscription(&env, subscription_id, &subscription);
+        println!("Subscription cancelled: ID: {:?}, By: {:?}", subscription_id, caller); // Debug print
+    }
+
+    // View a subscription
+    pub fn get_subscription(env: Env, subscription_id: u64) -> Subscription {
+        env.storage().persistent().get(&(symbol_short!("sub"), subscription_id)).unwrap_or_else(|| panic!("subscription not found"))
+    }
+
+    // Serialize the address's history entries in [start, end) to XDR for archival or migration
+    pub fn export_history(env: Env, address: Address, start: u32, end: u32) -> Bytes {
+        Self::require_history_access(&env, &address);
+        Self::require_within_limit(&env, end.saturating_sub(start), |limits| limits.max_history_per_tx, Error::BatchTooLarge);
+        let end = end.min(Self::get_history_len(&env, &address));
+        if start > end {
+            panic!("invalid range");
+        }
+        Self::get_payments_range(&env, &address, start, end).to_xdr(&env)
+    }
+
+    // Append records exported from a previous deployment to the owner's history.
+    // Records are taken as attested by the owner; every record must have been sent by them.
+    // They are marked as imported (see is_imported) and announced with an `imported` event
+    // rather than a `payment` one, so they cannot pass for payments settled here.
+    pub fn import_history(env: Env, owner: Address, data: Bytes) -> u32 {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        let payments = Vec::<Payment>::from_xdr(&env, &data).unwrap_or_else(|_| panic!("invalid history data"));
+        if payments.len() > MAX_IMPORT_RECORDS {
+            panic!("too many records");
+        }
+        Self::require_within_limit(&env, payments.len(), |limits| limits.max_history_per_tx, Error::BatchTooLarge);
+        for payment in payments.iter() {
+            if payment.from != owner {
+                panic!("record not sent by owner");
+            }
+            let payment_id = Self::store_payment(&env, payment, symbol_short!("imported"));
+            env.storage().persistent().set(&(symbol_short!("imported"), payment_id), &true);
+        }
+        println!("History imported: Owner: {:?}, Records: {:?}", owner, payments.len()); // Debug print
+        payments.len()
+    }
+
+    // Whether a payment record was imported with import_history rather than settled here
+    pub fn is_imported(env: Env, payment_id: u64) -> bool {
+        env.storage().persistent().has(&(symbol_short!("imported"), payment_id))
+    }
+
+    // Payment entry point for other contracts: `caller` authorizes exactly these arguments
+    // (a contract invoking this directly authorizes implicitly) and the memo is forwarded
+    // in a `memo` event as well as the payment record. The caller must also authorize the
+    // nested token transfer, e.g. with `authorize_as_current_contract`.
+    pub fn pay_from_contract(env: Env, caller: Address, to: Address, token_id: Address, amount: i128, memo: Memo) -> u64 {
+        caller.require_auth_for_args((to.clone(), token_id.clone(), amount, memo.clone()).into_val(&env));
+        Self::record_activity(&env, &caller);
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &caller);
+        Self::require_not_frozen(&env, &to);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_no_confirmation(&env, &caller, &token_id, amount);
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&caller, &to, &amount);
+        Self::check_alert(&env, &token_id, &caller, &to, amount);
+
+        env.events().publish((symbol_short!("memo"), caller.clone(), to.clone()), (token_id.clone(), amount, memo.clone()));
+        let payment_id = Self::record_payment(&env, &token_id, Payment {
+            from: caller.clone(),
+            to: to.clone(),
+            amount,
+            message: memo,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Contract payment: Caller: {:?}, To: {:?}, Amount: {:?}", caller, to, amount); // Debug print
+        payment_id
+    }
+
+    // Token the owner prefers to be paid in
+    pub fn set_default_token(env: Env, owner: Address, token_id: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().set(&(symbol_short!("def_token"), owner.clone()), &token_id);
+        println!("Default token set: Owner: {:?}, Token ID: {:?}", owner, token_id); // Debug print
+    }
+
+    pub fn get_default_token(env: Env, owner: Address) -> Option<Address> {
+        env.storage().persistent().get(&(symbol_short!("def_token"), owner))
+    }
+
+    // transfer in the recipient's default token, falling back to the sender's
+    pub fn transfer_default(env: Env, from: Address, to: Address, amount: i128, message: String) -> bool {
+        Self::transfer_default_with_memo(env, from, to, amount, Memo::Text(message))
+    }
+
+    // transfer_default with a structured memo
+    pub fn transfer_default_with_memo(env: Env, from: Address, to: Address, amount: i128, memo: Memo) -> bool {
+        let token_id = Self::resolve_default_token(&env, &to, &from);
+        Self::transfer_with_memo(env, token_id, from, to, amount, memo, None)
+    }
+
+    // request_payment in the requester's default token, falling back to the payer's
+    pub fn request_payment_default(env: Env, requester: Address, payer: Address, amount: i128, message: String) -> u64 {
+        let token_id = Self::resolve_default_token(&env, &requester, &payer);
+        Self::request_payment(env, requester, payer, token_id, amount, message, None)
+    }
+
+    // Preview a transfer without changing state. `reason` is `ok`, or explains a rejection
+    // (`invalid`, `frozen`, `rate_lim`, `balance`), or `confirm` when it will be held for
+    // confirmation.
+    // `fee` is the protocol fee charged on top, so `net_amount` is the full amount.
+    pub fn quote_transfer(env: Env, from: Address, to: Address, token_id: Address, amount: i128) -> Quote {
+        let fee = Self::transfer_fee(&env, amount);
+        let quote = |allowed: bool, reason: Symbol| Quote {
+            fee,
+            net_amount: amount,
+            allowed,
+            reason,
+        };
+        if let Some(reason) = Self::transfer_rejection(&env, &from, &to, amount) {
+            return quote(false, reason);
+        }
+        let roundup = match Self::get_roundup(env.clone(), from.clone()) {
+            Some((charity, round_to)) if !Self::is_frozen(env.clone(), charity.clone()) => (round_to - amount % round_to) % round_to,
+            _ => 0,
+        };
+        if TokenClient::new(&env, &token_id).balance(&from) < amount + roundup + fee {
+            return quote(false, symbol_short!("balance"));
+        }
+        if let Some((threshold, _)) = Self::get_confirmation_threshold(env.clone(), from, token_id) {
+            if amount > threshold {
+                return quote(true, symbol_short!("confirm"));
+            }
+        }
+        quote(true, symbol_short!("ok"))
+    }
+
+    // Escrow `total` of a token for recipients committed to by `merkle_root`
+    pub fn create_airdrop(env: Env, funder: Address, token_id: Address, total: i128, merkle_root: BytesN<32>, expiry: u64) -> u64 {
+        funder.require_auth();
+        Self::record_activity(&env, &funder);
+        Self::require_not_frozen(&env, &funder);
+        if total <= 0 {
+            panic!("amount must be positive");
+        }
+        if expiry <= env.ledger().timestamp() {
+            panic!("expiry must be in the future");
+        }
+        Self::require_no_confirmation(&env, &funder, &token_id, total);
+        Self::fund_escrow(&env, &token_id, &funder, total);
+        Self::check_alert(&env, &token_id, &funder, &env.current_contract_address(), total);
+
+        let (payout_token, min_payout_bps) = Self::escrow_payout(&env, &funder, &token_id);
+        let airdrop_id = Self::next_id(&env, symbol_short!("drop_id"));
+        Self::set_airdrop(&env, airdrop_id, &Airdrop {
+            funder: funder.clone(),
+            token_id,
+            merkle_root,
+            remaining: total,
+            expiry,
+            payout_token,
+            min_payout_bps,
+        });
+        Self::update_stats(&env, |stats| stats.open_escrows += 1);
+        println!("Airdrop created: ID: {:?}, Funder: {:?}, Total: {:?}", airdrop_id, funder, total); // Debug print
+        airdrop_id
+    }
+
+    // Claim an airdrop allocation by proving (recipient, amount) is a leaf of the Merkle root.
+    // Leaves are sha256(recipient XDR || amount XDR); sibling pairs are hashed in sorted order.
+    pub fn claim_airdrop(env: Env, airdrop_id: u64, recipient: Address, amount: i128, proof: Vec<BytesN<32>>) {
+        let mut airdrop = Self::get_airdrop(env.clone(), airdrop_id);
+        Self::require_not_frozen(&env, &recipient);
+        if env.ledger().timestamp() >= airdrop.expiry {
+            panic!("airdrop expired");
+        }
+        let claimed_key = (symbol_short!("dropclm"), airdrop_id, recipient.clone());
+        if env.storage().persistent().has(&claimed_key) {
+            panic!("airdrop already claimed");
+        }
+        let mut node = Self::airdrop_leaf(&env, &recipient, amount);
+        for sibling in proof.iter() {
+            let (first, second) = if node < sibling { (node, sibling) } else { (sibling, node) };
+            let mut pair = Bytes::from_array(&env, &first.to_array());
+            pair.extend_from_array(&second.to_array());
+            node = env.crypto().sha256(&pair).into();
+        }
+        if node != airdrop.merkle_root {
+            panic!("invalid merkle proof");
+        }
+        if amount <= 0 || amount > airdrop.remaining {
+            panic!("insufficient airdrop funds");
+        }
+
+        airdrop.remaining -= amount;
+        Self::set_airdrop(&env, airdrop_id, &airdrop);
+        if airdrop.remaining == 0 {
+            Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+        }
+        env.storage().persistent().set(&claimed_key, &true);
+
+        Self::release_escrow(&env, &airdrop.token_id, &recipient, amount, &airdrop.payout_token, airdrop.min_payout_bps);
+        println!("Airdrop claimed: ID: {:?}, Recipient: {:?}, Amount: {:?}", airdrop_id, recipient, amount); // Debug print
+    }
+
+    // Return unclaimed airdrop funds to the funder once the airdrop has expired
+    pub fn reclaim_airdrop(env: Env, airdrop_id: u64) -> i128 {
+        let mut airdrop = Self::get_airdrop(env.clone(), airdrop_id);
+        airdrop.funder.require_auth();
+        Self::record_activity(&env, &airdrop.funder);
+        Self::require_not_frozen(&env, &airdrop.funder);
+        if env.ledger().timestamp() < airdrop.expiry {
+            panic!("airdrop not expired");
+        }
+        let amount = airdrop.remaining;
+        airdrop.remaining = 0;
+        Self::set_airdrop(&env, airdrop_id, &airdrop);
+        if amount > 0 {
+            Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+            let token = TokenClient::new(&env, &airdrop.token_id);
+            token.transfer(&env.current_contract_address(), &airdrop.funder, &amount);
+        }
+        amount
+    }
+
+    // Get an airdrop by ID
+    pub fn get_airdrop(env: Env, airdrop_id: u64) -> Airdrop {
+        let key = (symbol_short!("airdrop"), airdrop_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("airdrop not found"))
+    }
+
+    // Check whether a recipient has already claimed from an airdrop
+    pub fn is_airdrop_claimed(env: Env, airdrop_id: u64, recipient: Address) -> bool {
+        env.storage().persistent().has(&(symbol_short!("dropclm"), airdrop_id, recipient))
+    }
+
+    // Emit an `alert` event for any outgoing transfer of `token_id` at or above `amount`
+    pub fn set_alert_threshold(env: Env, owner: Address, token_id: Address, amount: i128) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        env.storage().persistent().set(&(symbol_short!("alert"), owner.clone(), token_id.clone()), &amount);
+        println!("Alert threshold set: Owner: {:?}, Token ID: {:?}, Amount: {:?}", owner, token_id, amount); // Debug print
+    }
+
+    // Stop alerting on the owner's transfers of `token_id`
+    pub fn clear_alert_threshold(env: Env, owner: Address, token_id: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().remove(&(symbol_short!("alert"), owner, token_id));
+    }
+
+    pub fn get_alert_threshold(env: Env, owner: Address, token_id: Address) -> Option<i128> {
+        env.storage().persistent().get(&(symbol_short!("alert"), owner, token_id))
+    }
+
+    // Net signed positions per counterparty and settle only the residuals: a positive net
+    // pays the counterparty, a negative net is pulled from them and needs their signature.
+    // Returns the number of token transfers executed.
+    pub fn net_settle(env: Env, token_id: Address, from: Address, positions: Vec<(Address, i128)>) -> u32 {
+        from.require_auth();
+        Self::require_within_limit(&env, positions.len(), |limits| limits.max_recipients_per_batch, Error::TooManyRecipients);
+        Self::require_not_frozen(&env, &from);
+        Self::record_activity(&env, &from);
+        let mut net: Map<Address, i128> = Map::new(&env);
+        for (counterparty, amount) in positions.iter() {
+            Self::require_not_frozen(&env, &counterparty);
+            if counterparty == from {
+                panic!("cannot settle with self");
+            }
+            net.set(counterparty.clone(), net.get(counterparty).unwrap_or(0) + amount);
+        }
+
+        let token = TokenClient::new(&env, &token_id);
+        let batch_id = Self::next_id(&env, symbol_short!("batch_id"));
+        let message = String::from_str(&env, "Net settlement");
+        let mut transfers = 0u32;
+        for (counterparty, amount) in net.iter() {
+            let (payer, payee, amount) = match amount {
+                0 => continue,
+                a if a > 0 => (from.clone(), counterparty, a),
+                a => {
+                    counterparty.require_auth();
+                    (counterparty, from.clone(), -a)
+                }
+            };
+            Self::require_no_confirmation(&env, &payer, &token_id, amount);
+            token.transfer(&payer, &payee, &amount);
+            Self::check_alert(&env, &token_id, &payer, &payee, amount);
+            Self::record_payment(&env, &token_id, Payment {
+                from: payer,
+                to: payee,
+                amount,
+                message: Memo::Text(message.clone()),
+                batch_id: Some(batch_id),
+                metadata: None,
+                attachments: Vec::new(&env),
+            });
+            transfers += 1;
+        }
+        println!("Net settlement: From: {:?}, Positions: {:?}, Transfers: {:?}", from, positions.len(), transfers); // Debug print
+        transfers
+    }
+
+    // Content hashes committed to by a message
+    pub fn get_attachments(env: Env, msg_id: u64) -> Vec<BytesN<32>> {
+        Self::get_message(&env, msg_id).unwrap_or_else(|| panic!("message not found")).attachments
+    }
+
+    // Contract-wide counters: recorded payments, volume per token, active (unsuspended)
+    // recurring plans, and open escrows (payment links, HTLCs, gifts, airdrops, conditional
+    // payments and vouchers, each voucher code counting once)
+    pub fn get_global_stats(env: Env) -> GlobalStats {
+        env.storage().persistent().get(&symbol_short!("stats")).unwrap_or_else(|| GlobalStats {
+            total_payments: 0,
+            volume: Map::new(&env),
+            active_plans: 0,
+            open_escrows: 0,
+        })
+    }
+
+    // Block an address from sending or receiving through the contract (admin only)
+    pub fn freeze_address(env: Env, address: Address) {
+        Self::require_admin(&env);
+        env.storage().persistent().set(&(symbol_short!("frozen"), address.clone()), &true);
+        env.events().publish((symbol_short!("frozen"), address.clone()), true);
+        println!("Address frozen: {:?}", address); // Debug print
+    }
+
+    // Lift a freeze (admin only)
+    pub fn unfreeze_address(env: Env, address: Address) {
+        Self::require_admin(&env);
+        env.storage().persistent().remove(&(symbol_short!("frozen"), address.clone()));
+        env.events().publish((symbol_short!("frozen"), address.clone()), false);
+        println!("Address unfrozen: {:?}", address); // Debug print
+    }
+
+    pub fn is_frozen(env: Env, address: Address) -> bool {
+        env.storage().persistent().has(&(symbol_short!("frozen"), address))
+    }
+
+    // Escrow `amount` of `token_id` for the sender's recurring plan; charges processed in
+    // that token draw from it without the sender's signature while it covers the plan amount.
+    // Each sender has a single plan, so the balance is held per (sender, token) rather than per
+    // plan ID, and it stays with the sender's plan if that plan is replaced.
+    pub fn prepay_plan(env: Env, from: Address, token_id: Address, amount: i128) -> i128 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        if !Self::charges_in(&payment, &token_id) {
+            panic!("plan is charged in another token");
+        }
+        payment.pinned_token = Some(token_id.clone());
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&from, &env.current_contract_address(), &amount);
+
+        let balance = Self::get_prepaid(env.clone(), from.clone(), token_id.clone()) + amount;
+        env.storage().persistent().set(&(symbol_short!("prepaid"), from.clone(), token_id.clone()), &balance);
+        println!("Plan prepaid: From: {:?}, Token ID: {:?}, Amount: {:?}, Balance: {:?}", from, token_id, amount, balance); // Debug print
+        balance
+    }
+
+    // Return the whole prepaid balance to the sender
+    pub fn withdraw_prepaid(env: Env, from: Address, token_id: Address) -> i128 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        let amount = Self::get_prepaid(env.clone(), from.clone(), token_id.clone());
+        if amount == 0 {
+            panic!("nothing prepaid");
+        }
+        env.storage().persistent().remove(&(symbol_short!("prepaid"), from.clone(), token_id.clone()));
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        if let Some(mut payment) = recurring_payments.get(from.clone()) {
+            if !payment.use_deposit {
+                payment.pinned_token = None;
+                recurring_payments.set(from.clone(), payment);
+                Self::set_recurring_payments(&env, &recurring_payments);
+            }
+        }
+        let token = TokenClient::new(&env, &token_id);
+        token.transfer(&env.current_contract_address(), &from, &amount);
+        println!("Prepaid withdrawn: From: {:?}, Token ID: {:?}, Amount: {:?}", from, token_id, amount); // Debug print
+        amount
+    }
+
+    pub fn get_prepaid(env: Env, from: Address, token_id: Address) -> i128 {
+        env.storage().persistent().get(&(symbol_short!("prepaid"), from, token_id)).unwrap_or(0)
+    }
+
+    // Escrow a payment released to `to` once `condition` returns true; the sender can take
+    // it back after `expiry`
+    pub fn create_conditional_payment(env: Env, from: Address, to: Address, token_id: Address, amount: i128, condition: Condition, expiry: u64) -> u64 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_not_frozen(&env, &from);
+        Self::require_not_frozen(&env, &to);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        if expiry <= env.ledger().timestamp() {
+            panic!("expiry must be in the future");
+        }
+        Self::require_no_confirmation(&env, &from, &token_id, amount);
+        Self::fund_escrow(&env, &token_id, &from, amount);
+        Self::check_alert(&env, &token_id, &from, &to, amount);
+
+        let (payout_token, min_payout_bps) = Self::escrow_payout(&env, &from, &token_id);
+        let payment_id = Self::next_id(&env, symbol_short!("cond_id"));
+        Self::set_conditional_payment(&env, payment_id, &ConditionalPayment {
+            from: from.clone(),
+            to: to.clone(),
+            token_id,
+            amount,
+            condition_contract: condition.contract,
+            condition_fn: condition.function,
+            args: condition.args,
+            expiry,
+            settled: false,
+            payout_token,
+            min_payout_bps,
+        });
+        Self::update_stats(&env, |stats| stats.open_escrows += 1);
+        println!("Conditional payment created: ID: {:?}, From: {:?}, To: {:?}, Amount: {:?}", payment_id, from, to, amount); // Debug print
+        payment_id
+    }
+
+    // Check the condition and pay out if it holds. Anyone may call this; it returns false
+    // (without trapping) if the condition is unmet or the condition contract fails.
+    pub fn execute_conditional_payment(env: Env, payment_id: u64) -> bool {
+        let mut payment = Self::get_conditional_payment(env.clone(), payment_id);
+        if payment.settled {
+            panic!("conditional payment already settled");
+        }
+        if env.ledger().timestamp() >= payment.expiry {
+            panic!("conditional payment expired");
+        }
+        Self::require_not_frozen(&env, &payment.to);
+        let condition = env.try_invoke_contract::<bool, soroban_sdk::Error>(&payment.condition_contract, &payment.condition_fn, payment.args.clone());
+        if !matches!(condition, Ok(Ok(true))) {
+            println!("Condition not met: ID: {:?}", payment_id); // Debug print
+            return false;
+        }
+
+        payment.settled = true;
+        Self::set_conditional_payment(&env, payment_id, &payment);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let (paid_token, paid) = Self::release_escrow(&env, &payment.token_id, &payment.to, payment.amount, &payment.payout_token, payment.min_payout_bps);
+        Self::record_payment(&env, &paid_token, Payment {
+            from: payment.from.clone(),
+            to: payment.to.clone(),
+            amount: paid,
+            message: Memo::Id(payment_id),
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Conditional payment executed: ID: {:?}, To: {:?}, Amount: {:?}", payment_id, payment.to, payment.amount); // Debug print
+        true
+    }
+
+    // Return an unexecuted conditional payment to the sender after it expires
+    pub fn refund_conditional_payment(env: Env, payment_id: u64) {
+        let mut payment = Self::get_conditional_payment(env.clone(), payment_id);
+        payment.from.require_auth();
+        Self::record_activity(&env, &payment.from);
+        Self::require_not_frozen(&env, &payment.from);
+        if payment.settled {
+            panic!("conditional payment already settled");
+        }
+        if env.ledger().timestamp() < payment.expiry {
+            panic!("conditional payment not expired");
+        }
+
+        payment.settled = true;
+        Self::set_conditional_payment(&env, payment_id, &payment);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let token = TokenClient::new(&env, &payment.token_id);
+        token.transfer(&env.current_contract_address(), &payment.from, &payment.amount);
+        println!("Conditional payment refunded: ID: {:?}, From: {:?}, Amount: {:?}", payment_id, payment.from, payment.amount); // Debug print
+    }
+
+    pub fn get_conditional_payment(env: Env, payment_id: u64) -> ConditionalPayment {
+        env.storage().persistent().get(&(symbol_short!("cond"), payment_id)).unwrap_or_else(|| panic!("conditional payment not found"))
+    }
+
+    // Register a route that splits payments between receivers by basis points
+    pub fn create_split_route(env: Env, owner: Address, receivers: Vec<(Address, u32)>) -> u64 {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        if receivers.is_empty() || Self::total_bps(&receivers) != Some(10_000) {
+            panic!("receiver shares must sum to 10000 bps");
+        }
+        let route_id = Self::next_id(&env, symbol_short!("route_id"));
+        env.storage().persistent().set(&(symbol_short!("route"), route_id), &SplitRoute {
+            owner: owner.clone(),
+            receivers,
+        });
+        println!("Split route created: ID: {:?}, Owner: {:?}", route_id, owner); // Debug print
+        route_id
+    }
+
+    // Pay `amount` through a split route; the last receiver also gets any rounding remainder
+    pub fn transfer_via_route(env: Env, from: Address, route_id: u64, token_id: Address, amount: i128, memo: Memo) -> bool {
+        from.require_auth();
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &from);
+        Self::record_activity(&env, &from);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::consume_rate_limit(&env, &from, 1);
+        Self::require_no_confirmation(&env, &from, &token_id, amount);
+        let route = Self::get_split_route(env.clone(), route_id);
+        let token = TokenClient::new(&env, &token_id);
+        let batch_id = Self::next_id(&env, symbol_short!("batch_id"));
+
+        let mut remaining = amount;
+        let last = route.receivers.len() - 1;
+        for (i, (receiver, bps)) in route.receivers.iter().enumerate() {
+            let share = if i as u32 == last { remaining } else { amount * bps as i128 / 10_000 };
+            remaining -= share;
+            if share == 0 {
+                continue;
+            }
+            Self::require_not_frozen(&env, &receiver);
+            token.transfer(&from, &receiver, &share);
+            Self::check_alert(&env, &token_id, &from, &receiver, share);
+            Self::record_payment(&env, &token_id, Payment {
+                from: from.clone(),
+                to: receiver.clone(),
+                amount: share,
+                message: memo.clone(),
+                batch_id: Some(batch_id),
+                metadata: None,
+                attachments: Vec::new(&env),
+            });
+        }
+        println!("Routed transfer: From: {:?}, Route: {:?}, Amount: {:?}", from, route_id, amount); // Debug print
+        true
+    }
+
+    pub fn get_split_route(env: Env, route_id: u64) -> SplitRoute {
+        env.storage().persistent().get(&(symbol_short!("route"), route_id)).unwrap_or_else(|| panic!("split route not found"))
+    }
+
+    // Non-trapping transfer for composing contracts: reports why a transfer did not happen
+    // instead of aborting. Transfers above the confirmation threshold are not held here;
+    // they return NeedsConfirmation and must go through transfer. No round-up is applied.
+    pub fn transfer_checked(env: Env, token_id: Address, from: Address, to: Address, amount: i128, message: String) -> TransferStatus {
+        Self::transfer_checked_with_memo(env, token_id, from, to, amount, Memo::Text(message))
+    }
+
+    // transfer_checked with a structured memo
+    pub fn transfer_checked_with_memo(env: Env, token_id: Address, from: Address, to: Address, amount: i128, memo: Memo) -> TransferStatus {
+        from.require_auth();
+        Self::check_memo(&env, &memo);
+        if Self::is_frozen(env.clone(), from.clone()) {
+            return TransferStatus::Frozen;
+        }
+        Self::record_activity(&env, &from);
+        Self::try_transfer_leg(&env, &token_id, &from, &to, amount, &memo, None)
+    }
+
+    // Non-trapping multi_transfer: each recipient gets its own status and failed legs
+    // do not undo the successful ones
+    pub fn multi_transfer_checked(env: Env, token_id: Address, from: Address, recipients: Vec<(Address, i128)>, message: String) -> Vec<TransferStatus> {
+        Self::multi_transfer_checked_with_memo(env, token_id, from, recipients, Memo::Text(message))
+    }
+
+    // multi_transfer_checked with a structured memo
+    pub fn multi_transfer_checked_with_memo(env: Env, token_id: Address, from: Address, recipients: Vec<(Address, i128)>, memo: Memo) -> Vec<TransferStatus> {
+        from.require_auth();
+        Self::require_within_limit(&env, recipients.len(), |limits| limits.max_recipients_per_batch, Error::TooManyRecipients);
+        Self::check_memo(&env, &memo);
+        let mut statuses = Vec::new(&env);
+        if Self::is_frozen(env.clone(), from.clone()) {
+            for _ in recipients.iter() {
+                statuses.push_back(TransferStatus::Frozen);
+            }
+            return statuses;
+        }
+        Self::record_activity(&env, &from);
+        let batch_id = Self::next_id(&env, symbol_short!("batch_id"));
+        for (to, amount) in recipients.iter() {
+            statuses.push_back(Self::try_transfer_leg(&env, &token_id, &from, &to, amount, &memo, Some(batch_id)));
+        }
+        statuses
+    }
+
+    // Issue (or return the already issued) receipt for a payment: the sha256 of the XDR of
+    // (from, to, token, amount, timestamp, message), which off-chain systems can check
+    // with verify_receipt. The hash only attests the stored record, so either party (or anyone
+    // else) can issue it without a signature.
+    pub fn issue_receipt(env: Env, payment_id: u64) -> BytesN<32> {
+        let key = (symbol_short!("receipt"), payment_id);
+        if let Some(hash) = env.storage().persistent().get(&key) {
+            return hash;
+        }
+        let payment: Payment = env.storage().persistent().get(&(symbol_short!("payment"), payment_id))
+            .unwrap_or_else(|| panic!("payment not found"));
+        let (token_id, timestamp): (Address, u64) = env.storage().persistent().get(&(symbol_short!("pay_info"), payment_id))
+            .unwrap_or_else(|| panic!("payment details unavailable"));
+        let preimage = (payment.from, payment.to, token_id, payment.amount, timestamp, payment.message).to_xdr(&env);
+        let hash: BytesN<32> = env.crypto().sha256(&preimage).into();
+        env.storage().persistent().set(&key, &hash);
+        env.events().publish((symbol_short!("receipt"), payment_id), hash.clone());
+        println!("Receipt issued: Payment ID: {:?}", payment_id); // Debug print
+        hash
+    }
+
+    // Check a receipt hash against the one issued for the payment
+    pub fn verify_receipt(env: Env, payment_id: u64, hash: BytesN<32>) -> bool {
+        env.storage().persistent().get(&(symbol_short!("receipt"), payment_id)) == Some(hash)
+    }
+
+    // Up to `limit` inbox messages starting at inbox position `start`; expired ephemeral
+    // messages are skipped but still take up their position
+    pub fn get_messages_page(env: Env, owner: Address, start: u32, limit: u32) -> Vec<Message> {
+        owner.require_auth();
+        Self::deliver_due(&env, &owner);
+        let ids = Self::get_inbox_ids(&env, &owner);
+        let end = ids.len().min(start.saturating_add(limit));
+        let mut messages = Vec::new(&env);
+        for i in start..end {
+            if let Some(message) = Self::get_message(&env, ids.get(i).unwrap()) {
+                messages.push_back(message);
+            }
+        }
+        messages
+    }
+
+    // Number of messages delivered since the owner last marked their inbox read
+    pub fn get_unread_count(env: Env, owner: Address) -> u32 {
+        env.storage().persistent().get(&(symbol_short!("unread"), owner)).unwrap_or(0)
+    }
+
+    pub fn mark_inbox_read(env: Env, owner: Address) {
+        owner.require_auth();
+        Self::record_activity(&env, &owner);
+        env.storage().persistent().remove(&(symbol_short!("unread"), owner));
+    }
+
+    // Relayed transfer: `from` only signs an auth entry for these arguments, while the relayer
+    // submits (and pays fees for) the transaction and receives `tip` out of `amount`
+    pub fn transfer_sponsored(env: Env, relayer: Address, from: Address, transfer: SponsoredTransfer) -> bool {
+        relayer.require_auth();
+        from.require_auth_for_args((relayer.clone(), transfer.clone()).into_val(&env));
+        let SponsoredTransfer { to, token_id, amount, memo, tip } = transfer;
+        Self::check_memo(&env, &memo);
+        Self::require_not_frozen(&env, &from);
+        Self::require_not_frozen(&env, &to);
+        Self::require_not_frozen(&env, &relayer);
+        Self::record_activity(&env, &from);
+        if amount <= 0 || tip < 0 || tip > amount {
+            panic!("invalid amount or tip");
+        }
+        Self::consume_rate_limit(&env, &from, 1);
+
+        Self::require_no_confirmation(&env, &from, &token_id, amount);
+        let token = TokenClient::new(&env, &token_id);
+        let net = amount - tip;
+        if net > 0 {
+            token.transfer(&from, &to, &net);
+            Self::check_alert(&env, &token_id, &from, &to, net);
+        }
+        if tip > 0 {
+            token.transfer(&from, &relayer, &tip);
+            Self::check_alert(&env, &token_id, &from, &relayer, tip);
+        }
+        Self::record_payment(&env, &token_id, Payment {
+            from: from.clone(),
+            to: to.clone(),
+            amount: net,
+            message: memo,
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Sponsored transfer: Relayer: {:?}, From: {:?}, To: {:?}, Amount: {:?}, Tip: {:?}", relayer, from, to, amount, tip); // Debug print
+        true
+    }
+
+    // Bound user-supplied batch sizes (admin only). Recipient lists, message lengths, and
+    // the number of history entries or plans one call reads or processes are checked
+    // against these, failing with TooManyRecipients, MessageTooLong or BatchTooLarge.
+    pub fn set_limits(env: Env, max_recipients_per_batch: u32, max_message_len: u32, max_history_per_tx: u32) {
+        Self::require_admin(&env);
+        if max_recipients_per_batch == 0 || max_message_len == 0 || max_history_per_tx == 0 {
+            panic!("limits must be positive");
+        }
+        env.storage().persistent().set(&symbol_short!("limits"), &Limits {
+            max_recipients_per_batch,
+            max_message_len,
+            max_history_per_tx,
+        });
+        println!("Limits set: Recipients: {:?}, Message length: {:?}, History per tx: {:?}", max_recipients_per_batch, max_message_len, max_history_per_tx); // Debug print
+    }
+
+    pub fn get_limits(env: Env) -> Option<Limits> {
+        env.storage().persistent().get(&symbol_short!("limits"))
+    }
+
+    // Net amount of `token_id` that `a` has paid `b` across all recorded payments, minus
+    // what `b` has paid `a`; positive means `b` owes `a`
+    pub fn get_net_position(env: Env, a: Address, b: Address, token_id: Address) -> i128 {
+        let (key, sign) = Self::net_position_key(&a, &b, &token_id);
+        sign * env.storage().persistent().get::<_, i128>(&key).unwrap_or(0)
+    }
+
+    // Escrow a voucher worth `amount`, redeemable by whoever knows the preimage of `code_hash`
+    pub fn issue_voucher(env: Env, issuer: Address, token_id: Address, amount: i128, code_hash: BytesN<32>, expiry: u64) {
+        Self::issue_vouchers(env.clone(), issuer, token_id, amount, Vec::from_array(&env, [code_hash]), expiry);
+    }
+
+    // Issue one voucher of `amount` per code hash, escrowing the total in a single transfer
+    pub fn issue_vouchers(env: Env, issuer: Address, token_id: Address, amount: i128, code_hashes: Vec<BytesN<32>>, expiry: u64) {
+        issuer.require_auth();
+        Self::record_activity(&env, &issuer);
+        Self::require_not_frozen(&env, &issuer);
+        Self::require_within_limit(&env, code_hashes.len(), |limits| limits.max_recipients_per_batch, Error::TooManyRecipients);
+        if amount <= 0 || code_hashes.is_empty() {
+            panic!("amount must be positive");
+        }
+        if expiry <= env.ledger().timestamp() {
+            panic!("expiry must be in the future");
+        }
+        Self::require_no_confirmation(&env, &issuer, &token_id, amount * code_hashes.len() as i128);
+        Self::fund_escrow(&env, &token_id, &issuer, amount * code_hashes.len() as i128);
+        Self::check_alert(&env, &token_id, &issuer, &env.current_contract_address(), amount * code_hashes.len() as i128);
+
+        let (payout_token, min_payout_bps) = Self::escrow_payout(&env, &issuer, &token_id);
+        for code_hash in code_hashes.iter() {
+            let key = (symbol_short!("voucher"), code_hash);
+            if env.storage().persistent().has(&key) {
+                panic!("voucher code already used");
+            }
+            env.storage().persistent().set(&key, &Voucher {
+                issuer: issuer.clone(),
+                token_id: token_id.clone(),
+                amount,
+                expiry,
+                redeemed: false,
+                payout_token: payout_token.clone(),
+                min_payout_bps,
+            });
+        }
+        Self::update_stats(&env, |stats| stats.open_escrows += code_hashes.len());
+        println!("Vouchers issued: Issuer: {:?}, Count: {:?}, Amount: {:?}", issuer, code_hashes.len(), amount); // Debug print
+    }
+
+    // Redeem a voucher by revealing its code, returning the amount paid out (in the payout
+    // token, if the voucher has one)
+    pub fn redeem_voucher(env: Env, code_preimage: Bytes, recipient: Address) -> i128 {
+        Self::require_not_frozen(&env, &recipient);
+        let code_hash: BytesN<32> = env.crypto().sha256(&code_preimage).into();
+        let mut voucher = Self::get_voucher(env.clone(), code_hash.clone());
+        if voucher.redeemed {
+            panic!("voucher already redeemed");
+        }
+        if env.ledger().timestamp() > voucher.expiry {
+            panic!("voucher expired");
+        }
+
+        voucher.redeemed = true;
+        env.storage().persistent().set(&(symbol_short!("voucher"), code_hash.clone()), &voucher);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let (paid_token, paid) = Self::release_escrow(&env, &voucher.token_id, &recipient, voucher.amount, &voucher.payout_token, voucher.min_payout_bps);
+        Self::record_payment(&env, &paid_token, Payment {
+            from: voucher.issuer.clone(),
+            to: recipient.clone(),
+            amount: paid,
+            message: Memo::Hash(code_hash),
+            batch_id: None,
+            metadata: None,
+            attachments: Vec::new(&env),
+        });
+        println!("Voucher redeemed: Recipient: {:?}, Amount: {:?}", recipient, paid); // Debug print
+        paid
+    }
+
+    // Return an expired, unredeemed voucher's funds to its issuer
+    pub fn reclaim_voucher(env: Env, code_hash: BytesN<32>) -> i128 {
+        let mut voucher = Self::get_voucher(env.clone(), code_hash.clone());
+        voucher.issuer.require_auth();
+        Self::record_activity(&env, &voucher.issuer);
+        Self::require_not_frozen(&env, &voucher.issuer);
+        if voucher.redeemed {
+            panic!("voucher already redeemed");
+        }
+        if env.ledger().timestamp() <= voucher.expiry {
+            panic!("voucher not expired");
+        }
+
+        voucher.redeemed = true;
+        env.storage().persistent().set(&(symbol_short!("voucher"), code_hash), &voucher);
+        Self::update_stats(&env, |stats| stats.open_escrows = stats.open_escrows.saturating_sub(1));
+
+        let token = TokenClient::new(&env, &voucher.token_id);
+        token.transfer(&env.current_contract_address(), &voucher.issuer, &voucher.amount);
+        println!("Voucher reclaimed: Issuer: {:?}, Amount: {:?}", voucher.issuer, voucher.amount); // Debug print
+        voucher.amount
+    }
+
+    pub fn get_voucher(env: Env, code_hash: BytesN<32>) -> Voucher {
+        env.storage().persistent().get(&(symbol_short!("voucher"), code_hash)).unwrap_or_else(|| panic!("voucher not found"))
+    }
+
+    // Queue a message that enters the recipient's inbox at `deliver_at`. Until then it is
+    // kept out of message storage; reading the inbox or deliver_due_messages delivers it.
+    pub fn schedule_message(env: Env, from: Address, to: Address, text: String, deliver_at: u64) -> u64 {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        Self::require_within_limit(&env, text.len(), |limits| limits.max_message_len, Error::MessageTooLong);
+        if deliver_at <= env.ledger().timestamp() {
+            panic!("delivery time must be in the future");
+        }
+        Self::consume_rate_limit(&env, &from, 1);
+
+        let msg_id = Self::next_id(&env, symbol_short!("msg_id"));
+        let mut scheduled = Self::get_scheduled_messages(&env, &to);
+        scheduled.push_back((msg_id, Message {
+            from: from.clone(),
+            text,
+            sent_at: deliver_at,
+            attachments: Vec::new(&env),
+        }));
+        env.storage().persistent().set(&(symbol_short!("sched"), to.clone()), &scheduled);
+        println!("Message scheduled: ID: {:?}, From: {:?}, To: {:?}, Deliver at: {:?}", msg_id, from, to, deliver_at); // Debug print
+        msg_id
+    }
+
+    // Keeper entry point: move the owner's due scheduled messages into their inbox
+    pub fn deliver_due_messages(env: Env, owner: Address) -> u32 {
+        Self::deliver_due(&env, &owner)
+    }
+
+    // Pay `legs` (token, amount) to the plan's recipient alongside its main amount each cycle.
+    // All legs are paid from the sender's wallet and either all succeed or none are paid.
+    pub fn set_recurring_legs(env: Env, from: Address, legs: Vec<(Address, i128)>) {
+        from.require_auth();
+        Self::record_activity(&env, &from);
+        for (_, amount) in legs.iter() {
+            if amount <= 0 {
+                panic!("amount must be positive");
+            }
+        }
+        let mut recurring_payments = Self::get_recurring_payments(&env);
+        let mut payment = recurring_payments.get(from.clone()).unwrap_or_else(|| panic!("recurring payment not found"));
+        if !legs.is_empty() && (payment.use_deposit || payment.payout_token.is_some()) {
+            panic!("bundles must be paid from the wallet in their own tokens");
+        }
+        payment.extra_legs = legs.clone();
+        recurring_payments.set(from.clone(), payment);
+        Self::set_recurring_payments(&env, &recurring_payments);
+        println!("Recurring legs set: From: {:?}, Legs: {:?}", from, legs); // Debug print
+    }
+
+    // Set the protocol fee, in basis points, that transfers charge the sender on top of the
+    // amount (admin only)
+    pub fn set_protocol_fee(env: Env, fee_bps: u32) {
+        Self::require_admin(&env);
+        if fee_bps > 10_000 {
+            panic!("fee cannot exceed 100%");
+        }
+        env.storage().persistent().set(&symbol_short!("fee_bps"), &fee_bps);
+        println!("Protocol fee set: Bps: {:?}", fee_bps); // Debug print
+    }
+
+    pub fn get_protocol_fee(env: Env) -> u32 {
+        env.storage().persistent().get(&symbol_short!("fee_bps")).unwrap_or(0)
+    }
+
+    // Set the share of the protocol fee, in basis points, credited to the sender's referrer
+    pub fn set_referral_reward(env: Env, reward_bps: u32) {
+        Self::require_admin(&env);
+        if reward_bps > 10_000 {
+            panic!("reward cannot exceed 100%");
+        }
+        env.storage().persistent().set(&symbol_short!("ref_bps"), &reward_bps);
+        println!("Referral reward set: Bps: {:?}", reward_bps); // Debug print
+    }
+
+    // Protocol fees of a token collected so far, net of referral rewards
+    pub fn get_collected_fees(env: Env, token_id: Address) -> i128 {
+        env.storage().persistent().get(&(symbol_short!("fees"), token_id)).unwrap_or(0)
+    }
+
+    // Pay the collected protocol fees of a token out to the admin
+    pub fn withdraw_fees(env: Env, token_id: Address) -> i128 {
+        let admin = Self::require_admin(&env);
+        let fees = Self::get_collected_fees(env.clone(), token_id.clone());
+        if fees == 0 {
+            panic!("no fees to withdraw");
+        }
+        env.storage().persistent().remove(&(symbol_short!("fees"), token_id.clone()));
+        TokenClient::new(&env, &token_id).transfer(&env.current_contract_address(), &admin, &fees);
+        println!("Fees withdrawn: Token: {:?}, Amount: {:?}", token_id, fees); // Debug print
+        fees
+    }
+
+    // Record who referred `user`. A referrer can only be set once.
+    pub fn register_referrer(env: Env, user: Address, referrer: Address) {
+        user.require_auth();
+        Self::record_activity(&env, &user);
+        if user == referrer {
+            panic!("cannot refer yourself");
+        }
+        let key = (symbol_short!("referrer"), user.clone());
+        if env.storage().persistent().has(&key) {
+            panic!("referrer already registered");
+        }
+        env.storage().persistent().set(&key, &referrer);
+        env.events().publish((symbol_short!("referral"), referrer.clone()), user.clone());
+        println!("Referrer registered: User: {:?}, Referrer: {:?}", user, referrer); // Debug print
+    }
+
+    // Get the referrer of a user, if any
+    pub fn get_referrer(env: Env, user: Address) -> Option<Address> {
+        env.storage().persistent().get(&(symbol_short!("referrer"), user))
+    }
+
+    // Get the referral rewards a referrer can claim in a token
+    pub fn get_referral_rewards(env: Env, referrer: Address, token_id: Address) -> i128 {
+        env.storage().persistent().get(&(symbol_short!("ref_rew"), referrer, token_id)).unwrap_or(0)
+    }
+
+    // Pay out a referrer's accrued rewards in a token
+    pub fn claim_referral_rewards(env: Env, referrer: Address, token_id: Address) -> i128 {
+        referrer.require_auth();
+        Self::record_activity(&env, &referrer);
+        Self::require_not_frozen(&env, &referrer);
+        let key = (symbol_short!("ref_rew"), referrer.clone(), token_id.clone());
+        let rewards: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+        if rewards == 0 {
+            panic!("no rewards to claim");
+        }
+        env.storage().persistent().remove(&key);
+        TokenClient::new(&env, &token_id).transfer(&env.current_contract_address(), &referrer, &rewards);
+        println!("Referral rewards claimed: Referrer: {:?}, Token: {:?}, Amount: {:?}", referrer, token_id, rewards); // Debug print
+        rewards
+    }
+
+    fn next_id(env: &Env, counter: Symbol) -> u64 {
+        let id: u64 = env.storage().persistent().get(&counter).unwrap_or(0);
+        env.storage().persistent().set(&counter, &(id + 1));
+        id
+    }
+
+    fn get_payment_link(env: &Env, link_id: u64) -> PaymentLink {
+        let key = (symbol_short!("link"), link_id);
+        env.storage().persistent().get(&key).unwrap_or_else(|| panic!("link not found"))
+    }
+
+    fn set_htlc(env: &Env, htlc_id: u64, htlc: &Htlc) {
+        let key = (symbol_short!("htlc"), htlc_id);
+        env.storage().persistent().set(&key, htlc);
+    }
+
+    fn set_payment_link(env: &Env, link_id: u64, link: &PaymentLink) {
+        let key = (symbol_short!("link"), link_id);
+        env.storage().persistent().set(&key, link);
+    }
+
+    fn get_spender_allowance(env: &Env, owner: &Address, spender: &Address) -> Option<SpenderAllowance> {
+        let key = (symbol_short!("spender"), owner.clone(), spender.clone());
+        env.storage().persistent().get(&key)
+    }
+
+    fn set_spender_allowance(env: &Env, owner: &Address, spender: &Address, allowance: &SpenderAllowance) {
+        let key = (symbol_short!("spender"), owner.clone(), spender.clone());
+        env.storage().persistent().set(&key, allowance);
+    }
+
+    fn set_campaign(env: &Env, campaign_id: u64, campaign: &Campaign) {
+        let key = (symbol_short!("campaign"), campaign_id);
+        env.storage().persistent().set(&key, campaign);
+    }
+
+    fn set_payroll(env: &Env, payroll_id: u64, payroll: &Payroll) {
+        let key = (symbol_short!("payroll"), payroll_id);
+        env.storage().persistent().set(&key, payroll);
+    }
+
+    // Sum of basis-point shares, or None if a share exceeds 10000 bps or the sum overflows
+    fn total_bps(shares: &Vec<(Address, u32)>) -> Option<u32> {
+        let mut total = 0u32;
+        for (_, bps) in shares.iter() {
+            if bps > 10_000 {
+                return None;
+            }
+            total = total.checked_add(bps)?;
+        }
+        Some(total)
+    }
+
+    fn require_admin(env: &Env) -> Address {
+        let admin: Address = env.storage().persistent().get(&symbol_short!("admin")).unwrap_or_else(|| panic!("not initialized"));
+        admin.require_auth();
+        admin
+    }
+
+    fn get_liquidity_pool(env: &Env) -> Address {
+        env.storage().persistent().get(&symbol_short!("pool")).unwrap_or_else(|| panic!("liquidity pool not set"))
+    }
+
+    fn private_balances(env: &Env) -> bool {
+        env.storage().persistent().get(&symbol_short!("priv_bal")).unwrap_or(false)
+    }
+
+    // Current (window start, transfers used) for an address, starting a new window if the old one elapsed
+    fn get_rate_window(env: &Env, address: &Address, limit: &RateLimit) -> (u32, u32) {
+        let key = (symbol_short!("rate"), address.clone());
+        let current_ledger = env.ledger().sequence();
+        match env.storage().persistent().get::<_, (u32, u32)>(&key) {
+            Some((window_start, used)) if current_ledger < window_start + limit.window_ledgers => (window_start, used),
+            _ => (current_ledger, 0),
+        }
+    }
+
+    // Count `transfers` against the sender's rate limit, if one is configured
+    fn consume_rate_limit(env: &Env, address: &Address, transfers: u32) {
+        let Some(limit) = env.storage().persistent().get::<_, RateLimit>(&symbol_short!("ratelimit")) else {
+            return;
+        };
+        let (window_start, used) = Self::get_rate_window(env, address, &limit);
+        if used + transfers > limit.max_transfers {
+            panic!("rate limit exceeded");
+        }
+        let key = (symbol_short!("rate"), address.clone());
+        env.storage().persistent().set(&key, &(window_start, used + transfers));
+    }
+
+    // Whether `transfers` more would stay within the sender's rate limit, without counting them
+    fn within_rate_limit(env: &Env, address: &Address, transfers: u32) -> bool {
+        match env.storage().persistent().get::<_, RateLimit>(&symbol_short!("ratelimit")) {
+            Some(limit) => Self::get_rate_window(env, address, &limit).1 + transfers <= limit.max_transfers,
+            None => true,
+        }
+    }
+
+    fn set_deposit(env: &Env, owner: &Address, token_id: &Address, balance: i128) {
+        let key = (symbol_short!("deposit"), owner.clone(), token_id.clone());
+        env.storage().persistent().set(&key, &balance);
+    }
+
+    // Move an escrow's funds into this contract from the owner's wallet or, if they chose so,
+    // their internal balance (which the contract already holds)
+    fn fund_escrow(env: &Env, token_id: &Address, from: &Address, amount: i128) {
+        if Self::get_escrow_use_deposit(env.clone(), from.clone()) {
+            Self::debit_deposit(env, from, token_id, amount);
+        } else {
+            TokenClient::new(env, token_id).transfer(from, &env.current_contract_address(), &amount);
+        }
+    }
+
+    // The payout settings a new escrow of `token_id` created by `owner` keeps for its lifetime
+    fn escrow_payout(env: &Env, owner: &Address, token_id: &Address) -> (Option<Address>, u32) {
+        match Self::get_escrow_payout_token(env.clone(), owner.clone(), token_id.clone()) {
+            Some((payout_token, min_payout_bps)) => (Some(payout_token), min_payout_bps),
+            None => (None, 0),
+        }
+    }
+
+    // Pay `amount` of an escrow's `token_id` to `to`, swapping it into the escrow's payout token
+    // if it has one. Returns the token and amount actually paid.
+    fn release_escrow(env: &Env, token_id: &Address, to: &Address, amount: i128, payout_token: &Option<Address>, min_payout_bps: u32) -> (Address, i128) {
+        let Some(payout_token) = payout_token else {
+            TokenClient::new(env, token_id).transfer(&env.current_contract_address(), to, &amount);
+            return (token_id.clone(), amount);
+        };
+        let pool = Self::get_liquidity_pool(env);
+        // The pool pulls the escrowed amount from this contract, which is not its direct caller
+        env.authorize_as_current_contract(Vec::from_array(env, [InvokerContractAuthEntry::Contract(SubContractInvocation {
+            context: ContractContext {
+                contract: token_id.clone(),
+                fn_name: symbol_short!("transfer"),
+                args: (env.current_contract_address(), pool.clone(), amount).into_val(env),
+            },
+            sub_invocations: Vec::new(env),
+        })]));
+        let min_amount_out = amount * min_payout_bps as i128 / 10_000;
+        let paid = LiquidityPoolClient::new(env, &pool).swap_exact_in(&env.current_contract_address(), to, token_id, payout_token, &amount, &min_amount_out);
+        (payout_token.clone(), paid)
+    }
+
+    // Take `amount` from an internal balance, returning what is left
+    fn debit_deposit(env: &Env, owner: &Address, token_id: &Address, amount: i128) -> i128 {
+        let balance = Self::get_deposit(env.clone(), owner.clone(), token_id.clone());
+        if balance < amount {
+            panic!("insufficient deposit");
+        }
+        Self::set_deposit(env, owner, token_id, balance - amount);
+        balance - amount
+    }
+
+    fn set_inheritance_plan(env: &Env, owner: &Address, plan: &InheritancePlan) {
+        env.storage().persistent().set(&(symbol_short!("inherit"), owner.clone()), plan);
+    }
+
+    // Reset the inactivity timer of the owner's inheritance plan, if they have one
+    fn record_activity(env: &Env, owner: &Address) {
+        if let Some(mut plan) = Self::get_inheritance_plan(env.clone(), owner.clone()) {
+            plan.last_active = env.ledger().timestamp();
+            Self::set_inheritance_plan(env, owner, &plan);
+        }
+    }
+
+    // Check that `session_addr` signed and holds an unexpired session of `owner` covering `op` and
+    // `amount`. Acting through a session the owner granted counts as the owner's activity.
+    fn require_session(env: &Env, owner: &Address, session_addr: &Address, op: Symbol, amount: i128) {
+        session_addr.require_auth();
+        let key = (symbol_short!("session"), owner.clone(), session_addr.clone());
+        let session: SessionKey = env.storage().persistent().get(&key).unwrap_or_else(|| panic!("session not found"));
+        if env.ledger().timestamp() > session.expiry {
+            panic!("session expired");
+        }
+        if !session.allowed_ops.contains(&op) {
+            panic!("operation not allowed for session");
+        }
+        if amount > session.max_amount {
+            panic!("amount exceeds session limit");
+        }
+        Self::record_activity(env, owner);
+    }
+
+    fn create_payment_request(env: &Env, request: PaymentRequest) -> u64 {
+        request.requester.require_auth();
+        Self::record_activity(env, &request.requester);
+        if request.amount <= 0 {
+            panic!("amount must be positive");
+        }
+        if let Some(metadata) = &request.metadata {
+            Self::check_metadata(metadata);
+        }
+        Self::consume_rate_limit(env, &request.requester, 1);
+        let request_id = Self::next_id(env, symbol_short!("req_id"));
+        Self::set_payment_request(env, request_id, &request);
+        println!("Payment requested: ID: {:?}, Requester: {:?}, Payer: {:?}, Amount: {:?}", request_id, request.requester, request.payer, request.amount); // Debug print
+        request_id
+    }
+
+    // Convert a USD amount in the oracle's decimals to base units of `token_id`
+    fn usd_to_token(env: &Env, token_id: &Address, usd_amount: i128) -> i128 {
+        let (oracle, max_staleness): (Address, u64) = env.storage().persistent().get(&symbol_short!("oracle")).unwrap_or_else(|| panic!("oracle not set"));
+        let price = PriceOracleClient::new(env, &oracle).lastprice(token_id).unwrap_or_else(|| panic!("no price for token"));
+        if env.ledger().timestamp() > price.timestamp + max_staleness {
+            panic!("oracle price is stale");
+        }
+        if price.price <= 0 {
+            panic!("invalid oracle price");
+        }
+        // `price` is the USD value of one whole token, so scale by the token's decimals
+        let token_decimals = TokenClient::new(env, token_id).decimals();
+        usd_amount * 10i128.pow(token_decimals) / price.price
+    }
+
+    // Late fee owed on paying `amount` of a request at the current time
+    fn late_fee(env: &Env, request: &PaymentRequest, amount: i128) -> i128 {
+        match request.due_date {
+            Some(due_date) if env.ledger().timestamp() > due_date + request.grace_period => {
+                amount * request.late_fee_bps as i128 / 10_000
+            }
+            _ => 0,
+        }
+    }
+
+    fn set_payment_request(env: &Env, request_id: u64, request: &PaymentRequest) {
+        let key = (symbol_short!("request"), request_id);
+        env.storage().persistent().set(&key, request);
+    }
+
+    fn update_reputation(env: &Env, address: &Address, update: impl FnOnce(&mut ReputationScore)) {
+        let mut score = Self::get_reputation(env.clone(), address.clone());
+        update(&mut score);
+        env.storage().persistent().set(&(symbol_short!("rep"), address.clone()), &score);
+    }
+
+    fn get_message(env: &Env, msg_id: u64) -> Option<Message> {
+        let key = (symbol_short!("msg"), msg_id);
+        env.storage().persistent().get(&key).or_else(|| env.storage().temporary().get(&key))
+    }
+
+    fn get_inbox_ids(env: &Env, owner: &Address) -> Vec<u64> {
+        env.storage().persistent().get(&(symbol_short!("inbox"), owner.clone())).unwrap_or_else(|| Vec::new(env))
+    }
+
+    fn deliver_message(env: &Env, recipient: &Address, msg_id: u64) {
+        let mut inbox = Self::get_inbox_ids(env, recipient);
+        inbox.push_back(msg_id);
+        env.storage().persistent().set(&(symbol_short!("inbox"), recipient.clone()), &inbox);
+        let unread = Self::get_unread_count(env.clone(), recipient.clone());
+        env.storage().persistent().set(&(symbol_short!("unread"), recipient.clone()), &(unread + 1));
+    }
+
+    fn get_gift(env: &Env, gift_id: u64) -> Gift {
+        env.storage().persistent().get(&(symbol_short!("gift"), gift_id)).unwrap_or_else(|| panic!("gift not found"))
+    }
+
+    fn set_gift(env: &Env, gift_id: u64, gift: &Gift) {
+        env.storage().persistent().set(&(symbol_short!("gift"), gift_id), gift);
+    }
+
+    fn set_subscription(env: &Env, subscription_id: u64, subscription: &Subscription) {
+        env.storage().persistent().set(&(symbol_short!("sub"), subscription_id), subscription);
+    }
+
+    fn resolve_default_token(env: &Env, preferred: &Address, fallback: &Address) -> Address {
+        Self::get_default_token(env.clone(), preferred.clone())
+            .or_else(|| Self::get_default_token(env.clone(), fallback.clone()))
+            .unwrap_or_else(|| panic!("no default token set"))
+    }
+
+    fn set_airdrop(env: &Env, airdrop_id: u64, airdrop: &Airdrop) {
+        let key = (symbol_short!("airdrop"), airdrop_id);
+        env.storage().persistent().set(&key, airdrop);
+    }
+
+    fn airdrop_leaf(env: &Env, recipient: &Address, amount: i128) -> BytesN<32> {
+        let mut leaf = recipient.clone().to_xdr(env);
+        leaf.append(&amount.to_xdr(env));
+        env.crypto().sha256(&leaf).into()
+    }
+
+    fn check_alert(env: &Env, token_id: &Address, from: &Address, to: &Address, amount: i128) {
+        if let Some(threshold) = Self::get_alert_threshold(env.clone(), from.clone(), token_id.clone()) {
+            if amount >= threshold {
+                env.events().publish((symbol_short!("alert"), from.clone()), (token_id.clone(), to.clone(), amount, env.ledger().timestamp()));
+            }
+        }
+    }
+
+    fn remove_transfer_intent(env: &Env, from: &Address, intent_id: u64) {
+        env.storage().persistent().remove(&(symbol_short!("intent"), intent_id));
+        let mut pending = Self::get_pending_intents(env.clone(), from.clone());
+        if let Some(i) = pending.first_index_of(intent_id) {
+            pending.remove(i);
+        }
+        env.storage().persistent().set(&(symbol_short!("intents"), from.clone()), &pending);
+    }
+
+    // Bound both the number of metadata entries and the length of each value
+    fn check_metadata(metadata: &Map<Symbol, String>) {
+        if metadata.len() > MAX_METADATA_ENTRIES {
+            panic!("too many metadata entries");
+        }
+        if metadata.values().iter().any(|value| value.len() > MAX_METADATA_VALUE_LEN) {
+            panic!("metadata value too long");
+        }
+    }
+
+    fn check_attachments(attachments: &Vec<BytesN<32>>) {
+        if attachments.len() > MAX_ATTACHMENTS {
+            panic!("too many attachments");
+        }
+    }
+
+    // Payment events already carry their attachments; messages get their own event for indexers
+    fn publish_attachments(env: &Env, msg_id: u64, from: &Address, attachments: &Vec<BytesN<32>>) {
+        if !attachments.is_empty() {
+            env.events().publish((symbol_short!("attach"), from.clone()), (msg_id, attachments.clone()));
+        }
+    }
+
+    fn update_stats(env: &Env, update: impl FnOnce(&mut GlobalStats)) {
+        let mut stats = Self::get_global_stats(env.clone());
+        update(&mut stats);
+        env.storage().persistent().set(&symbol_short!("stats"), &stats);
+    }
+
+    fn require_not_frozen(env: &Env, address: &Address) {
+        if Self::is_frozen(env.clone(), address.clone()) {
+            panic_with_error!(env, Error::Frozen);
+        }
+    }
+
+    fn set_conditional_payment(env: &Env, payment_id: u64, payment: &ConditionalPayment) {
+        env.storage().persistent().set(&(symbol_short!("cond"), payment_id), payment);
+    }
+
+    fn try_transfer_leg(env: &Env, token_id: &Address, from: &Address, to: &Address, amount: i128, memo: &Memo, batch_id: Option<u64>) -> TransferStatus {
+        match Self::transfer_rejection(env, from, to, amount) {
+            Some(reason) if reason == symbol_short!("frozen") => return TransferStatus::Frozen,
+            Some(reason) if reason == symbol_short!("rate_lim") => return TransferStatus::RateLimited,
+            Some(_) => return TransferStatus::Invalid,
+            None => {}
+        }
+        if let Some((threshold, _)) = Self::get_confirmation_threshold(env.clone(), from.clone(), token_id.clone()) {
+            if amount > threshold {
+                return TransferStatus::NeedsConfirmation;
+            }
+        }
+        let token = TokenClient::new(env, token_id);
+        if token.try_transfer(from, to, &amount).is_err() {
+            return TransferStatus::Failed;
+        }
+        // Only a leg that went through counts against the rate limit
+        Self::consume_rate_limit(env, from, 1);
+        Self::check_alert(env, token_id, from, to, amount);
+        Self::record_payment(env, token_id, Payment {
+            from: from.clone(),
+            to: to.clone(),
+            amount,
+            message: memo.clone(),
+            batch_id,
+            metadata: None,
+            attachments: Vec::new(env),
+        });
+        TransferStatus::Sent
+    }
+
+    // Only transfer, transfer_v2 and their memo variants hold a large payment for confirmation;
+    // every other outgoing path rejects amounts above the sender's threshold
+    fn require_no_confirmation(env: &Env, from: &Address, token_id: &Address, amount: i128) {
+        if let Some((threshold, _)) = Self::get_confirmation_threshold(env.clone(), from.clone(), token_id.clone()) {
+            if amount > threshold {
+                panic!("amount requires confirmation");
+            }
+        }
+    }
+
+    // Why a transfer would be rejected before any funds move, if it would: `invalid` (a
+    // non-positive amount or a self-transfer), `frozen` or `rate_lim`. Shared by quote_transfer
+    // and the transfer paths so a quote always matches what the transfer does.
+    fn transfer_rejection(env: &Env, from: &Address, to: &Address, amount: i128) -> Option<Symbol> {
+        if amount <= 0 || from == to {
+            return Some(symbol_short!("invalid"));
+        }
+        if Self::is_frozen(env.clone(), from.clone()) || Self::is_frozen(env.clone(), to.clone()) {
+            return Some(symbol_short!("frozen"));
+        }
+        if !Self::within_rate_limit(env, from, 1) {
+            return Some(symbol_short!("rate_lim"));
+        }
+        None
+    }
+
+    // Store a message and deliver it to `to`, once the sender (or their session) is authorized
+    fn store_message(env: &Env, from: Address, to: Address, text: String, ephemeral: bool, attachments: Vec<BytesN<32>>) -> u64 {
+        Self::require_within_limit(env, text.len(), |limits| limits.max_message_len, Error::MessageTooLong);
+        Self::check_attachments(&attachments);
+        Self::consume_rate_limit(env, &from, 1);
+
+        let msg_id = Self::next_id(env, symbol_short!("msg_id"));
+        let key = (symbol_short!("msg"), msg_id);
+        let message = Message {
+            from: from.clone(),
+            text,
+            sent_at: env.ledger().timestamp(),
+            attachments: attachments.clone(),
+        };
+        if ephemeral {
+            let ttl = Self::get_ephemeral_ttl(env.clone());
+            env.storage().temporary().set(&key, &message);
+            env.storage().temporary().extend_ttl(&key, ttl, ttl);
+        } else {
+            env.storage().persistent().set(&key, &message);
+        }
+        Self::deliver_message(env, &to, msg_id);
+        Self::publish_attachments(env, msg_id, &from, &attachments);
+        println!("Message sent: ID: {:?}, From: {:?}, To: {:?}, Ephemeral: {:?}", msg_id, from, to, ephemeral); // Debug print
+        msg_id
+    }
+
+    // Text memos are bounded like messages
+    fn check_memo(env: &Env, memo: &Memo) {
+        if let Memo::Text(text) = memo {
+            Self::require_within_limit(env, text.len(), |limits| limits.max_message_len, Error::MessageTooLong);
+        }
+    }
+
+    fn require_within_limit(env: &Env, count: u32, limit: fn(&Limits) -> u32, error: Error) {
+        if let Some(limits) = Self::get_limits(env.clone()) {
+            if count > limit(&limits) {
+                panic_with_error!(env, error);
+            }
+        }
+    }
+
+    // Each pair is stored once, ordered by address; the sign says which side the key favours
+    fn net_position_key(a: &Address, b: &Address, token_id: &Address) -> ((Symbol, Address, Address, Address), i128) {
+        if a < b {
+            ((symbol_short!("net"), a.clone(), b.clone(), token_id.clone()), 1)
+        } else {
+            ((symbol_short!("net"), b.clone(), a.clone(), token_id.clone()), -1)
+        }
+    }
+
+    fn update_net_position(env: &Env, from: &Address, to: &Address, token_id: &Address, amount: i128) {
+        if from == to {
+            return;
+        }
+        let (key, sign) = Self::net_position_key(from, to, token_id);
+        let position = env.storage().persistent().get::<_, i128>(&key).unwrap_or(0) + sign * amount;
+        env.storage().persistent().set(&key, &position);
+    }
+
+    fn execute_transfer(env: &Env, token_id: Address, from: Address, to: Address, amount: i128, memo: Memo, options: Option<TransferOptions>) -> TransferReceipt {
+        from.require_auth();
+        match Self::transfer_rejection(env, &from, &to, amount) {
+            Some(reason) if reason == symbol_short!("frozen") => panic_with_error!(env, Error::Frozen),
+            Some(reason) if reason == symbol_short!("rate_lim") => panic!("rate limit exceeded"),
+            Some(_) => panic!("invalid amount or recipient"),
+            None => {}
+        }
+        Self::record_activity(env, &from);
+        let TransferOptions { idempotency_key, metadata, attachments } = options.unwrap_or(TransferOptions {
+            idempotency_key: None,
+            metadata: None,
+            attachments: Vec::new(env),
+        });
+        if let Some(metadata) = &metadata {
+            Self::check_metadata(metadata);
+        }
+        Self::check_attachments(&attachments);
+        let idempotency_key = idempotency_key.map(|key| BytesN::<32>::try_from(key).unwrap_or_else(|_| panic!("idempotency key must be 32 bytes")));
+        Self::check_memo(env, &memo);
+        Self::use_idempotency_key(env, &from, idempotency_key);
+        Self::consume_rate_limit(env, &from, 1);
+        let token = TokenClient::new(env, &token_id);
+
+        println!("Initiating transfer: From: {:?}, To: {:?}, Amount: {:?}, Memo: {:?}", from, to, amount, memo); // Debug print
+
+        let mut payment = Payment {
+            from: from.clone(),
+            to: to.clone(),
+            amount,
+            message: memo,
+            batch_id: None,
+            metadata,
+            attachments,
+        };
+
+        // Large transfers wait for a second confirmation instead of executing now
+        if let Some((threshold, delay)) = Self::get_confirmation_threshold(env.clone(), from.clone(), token_id.clone()) {
+            if amount > threshold {
+                let intent_id = Self::next_id(env, symbol_short!("intent_id"));
+                env.storage().persistent().set(&(symbol_short!("intent"), intent_id), &TransferIntent {
+                    token_id,
+                    payment,
+                    unlock_at: env.ledger().timestamp() + delay,
+                });
+                let mut pending = Self::get_pending_intents(env.clone(), from.clone());
+                pending.push_back(intent_id);
+                env.storage().persistent().set(&(symbol_short!("intents"), from.clone()), &pending);
+                env.events().publish((symbol_short!("pending"), from.clone()), intent_id);
+                println!("Transfer pending confirmation: Intent: {:?}, From: {:?}, Amount: {:?}", intent_id, from, amount); // Debug print
+                return TransferReceipt {
+                    payment_id: None,
+                    intent_id: Some(intent_id),
+                    net_amount: amount,
+                    fee: Self::transfer_fee(env, amount),
+                    timestamp: env.ledger().timestamp(),
+                };
+            }
+        }
+
+        token.transfer(&from, &to, &amount);
+        let fee = Self::charge_fee(env, &token_id, &from, amount);
+        Self::check_alert(env, &token_id, &from, &to, amount);
+
+        // Round the amount up for charity, linking both records through a shared batch ID.
+        // Nothing is donated while the charity is frozen.
+        if let Some((charity, round_to)) = Self::get_roundup(env.clone(), from.clone()) {
+            let donation = (round_to - amount % round_to) % round_to;
+            if donation > 0 && !Self::is_frozen(env.clone(), charity.clone()) {
+                token.transfer(&from, &charity, &donation);
+                Self::check_alert(env, &token_id, &from, &charity, donation);
+                let batch_id = Self::next_id(env, symbol_short!("batch_id"));
+                payment.batch_id = Some(batch_id);
+                Self::record_payment(env, &token_id, Payment {
+                    from: from.clone(),
+                    to: charity,
+                    amount: donation,
+                    message: Memo::Text(String::from_str(env, "Round-up donation")),
+                    batch_id: Some(batch_id),
+                    metadata: None,
+                    attachments: Vec::new(env),
+                });
+            }
+        }
+
+        // Store payment record
+        let payment_id = Self::record_payment(env, &token_id, payment);
+
+        println!("Transfer successful: From: {:?}, To: {:?}, Amount: {:?}", from, to, amount); // Debug print
+        TransferReceipt {
+            payment_id: Some(payment_id),
+            intent_id: None,
+            net_amount: amount,
+            fee,
+            timestamp: env.ledger().timestamp(),
+        }
+    }
+
+    fn get_scheduled_messages(env: &Env, owner: &Address) -> Vec<(u64, Message)> {
+        env.storage().persistent().get(&(symbol_short!("sched"), owner.clone())).unwrap_or_else(|| Vec::new(env))
+    }
+
+    fn deliver_due(env: &Env, owner: &Address) -> u32 {
+        let scheduled = Self::get_scheduled_messages(env, owner);
+        let now = env.ledger().timestamp();
+        let mut pending = Vec::new(env);
+        let mut delivered = 0u32;
+        for (msg_id, message) in scheduled.iter() {
+            if message.sent_at > now {
+                pending.push_back((msg_id, message));
+                continue;
+            }
+            env.storage().persistent().set(&(symbol_short!("msg"), msg_id), &message);
+            Self::deliver_message(env, owner, msg_id);
+            delivered += 1;
+        }
+        if delivered > 0 {
+            let key = (symbol_short!("sched"), owner.clone());
+            if pending.is_empty() {
+                env.storage().persistent().remove(&key);
+            } else {
+                env.storage().persistent().set(&key, &pending);
+            }
+        }
+        delivered
+    }
+
+    // Pay every leg of a bundle, or none. Legs are first collected into the contract, so a leg
+    // the sender cannot pay returns the others and the cycle is reported as a failed charge.
+    // A leg the recipient cannot receive is credited to their internal deposit instead.
+    fn try_charge_bundle(env: &Env, token_id: &Address, from: &Address, payment: &RecurringPayment) -> bool {
+        from.require_auth();
+        let mut legs = Vec::from_array(env, [(token_id.clone(), payment.amount)]);
+        legs.append(&payment.extra_legs);
+        let contract = env.current_contract_address();
+        for (i, (leg_token, amount)) in legs.iter().enumerate() {
+            if TokenClient::new(env, &leg_token).try_transfer(from, &contract, &amount).is_err() {
+                for (collected_token, collected) in legs.slice(0..i as u32).iter() {
+                    Self::pay_or_credit(env, &collected_token, from, collected);
+                }
+                return false;
+            }
+        }
+        for (leg_token, amount) in legs.iter() {
+            Self::pay_or_credit(env, &leg_token, &payment.to, amount);
+        }
+        true
+    }
+
+    // Send `amount` held by the contract to `to`, crediting their internal deposit if the
+    // token refuses the transfer
+    fn pay_or_credit(env: &Env, token_id: &Address, to: &Address, amount: i128) {
+        if TokenClient::new(env, token_id).try_transfer(&env.current_contract_address(), to, &amount).is_err() {
+            let balance = Self::get_deposit(env.clone(), to.clone(), token_id.clone()) + amount;
+            Self::set_deposit(env, to, token_id, balance);
+        }
+    }
+
+    // Protocol fee on a transfer of `amount`
+    fn transfer_fee(env: &Env, amount: i128) -> i128 {
+        amount * Self::get_protocol_fee(env.clone()) as i128 / 10_000
+    }
+
+    // Charge the protocol fee on top of a transfer of `amount`. The sender's referrer is credited
+    // their share of it and the rest is kept for the admin, so referral rewards are always paid
+    // by the referred user rather than by the contract. Returns the fee.
+    fn charge_fee(env: &Env, token_id: &Address, from: &Address, amount: i128) -> i128 {
+        let fee = Self::transfer_fee(env, amount);
+        if fee == 0 {
+            return 0;
+        }
+        TokenClient::new(env, token_id).transfer(from, &env.current_contract_address(), &fee);
+        let mut reward = 0;
+        if let Some(referrer) = Self::get_referrer(env.clone(), from.clone()) {
+            let reward_bps: u32 = env.storage().persistent().get(&symbol_short!("ref_bps")).unwrap_or(0);
+            reward = fee * reward_bps as i128 / 10_000;
+            let key = (symbol_short!("ref_rew"), referrer, token_id.clone());
+            let rewards: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+            env.storage().persistent().set(&key, &(rewards + reward));
+        }
+        let fees = Self::get_collected_fees(env.clone(), token_id.clone());
+        env.storage().persistent().set(&(symbol_short!("fees"), token_id.clone()), &(fees + fee - reward));
+        fee
+    }
+}
+
+// Helpers for integration tests of contracts composing with this one
+#[cfg(any(test, feature = "testutils"))]
+pub mod testutils {
+    use super::{HistoryMode, Payment, PaymentMessagingSystem, PaymentMessagingSystemClient};
+    use soroban_sdk::testutils::{Address as _, Events};
+    use soroban_sdk::token::StellarAssetClient;
+    use soroban_sdk::{symbol_short, Address, Env, Symbol, TryFromVal, Vec};
+
+    // Register a fresh Stellar asset contract and return its address
+    pub fn register_token(env: &Env) -> Address {
+        let issuer = Address::generate(env);
+        env.register_stellar_asset_contract_v2(issuer).address()
+    }
+
+    // Register this contract alongside a fresh token
+    pub fn register_with_token<'a>(env: &Env) -> (PaymentMessagingSystemClient<'a>, Address) {
+        let contract_id = env.register_contract(None, PaymentMessagingSystem);
+        (PaymentMessagingSystemClient::new(env, &contract_id), register_token(env))
+    }
+
+    // Mint `amount` of a token registered with register_token to `to`
+    pub fn fund(env: &Env, token_id: &Address, to: &Address, amount: i128) {
+        StellarAssetClient::new(env, token_id).mint(to, &amount);
+    }
+
+    // (payment ID, payment) of the contract's payments: every stored record (imports excluded)
+    // in the Storage and Both history modes, and the payment events of the last invocation in
+    // the Events mode, where nothing is stored
+    pub fn payment_events(env: &Env, contract: &Address) -> Vec<(u64, Payment)> {
+        let mode = env.as_contract(contract, || PaymentMessagingSystem::get_history_mode(env.clone()));
+        if mode != HistoryMode::Events {
+            return stored_payments(env, contract);
+        }
+        let mut payments = Vec::new(env);
+        for (address, topics, data) in env.events().all().iter() {
+            let is_payment = topics.first()
+                .and_then(|topic| Symbol::try_from_val(env, &topic).ok())
+                .is_some_and(|topic| topic == symbol_short!("payment"));
+            if address == *contract && is_payment {
+                payments.push_back(<(u64, Payment)>::try_from_val(env, &data).unwrap());
+            }
+        }
+        payments
+    }
+
+    // Stored payment records in ID order, leaving out those brought in by import_history
+    fn stored_payments(env: &Env, contract: &Address) -> Vec<(u64, Payment)> {
+        env.as_contract(contract, || {
+            let storage = env.storage().persistent();
+            let count: u64 = storage.get(&symbol_short!("pay_id")).unwrap_or(0);
+            let mut payments = Vec::new(env);
+            for payment_id in 0..count {
+                if storage.has(&(symbol_short!("imported"), payment_id)) {
+                    continue;
+                }
+                if let Some(payment) = storage.get(&(symbol_short!("payment"), payment_id)) {
+                    payments.push_back((payment_id, payment));
+                }
+            }
+            payments
+        })
+    }
+
+    // Panic unless payment_events holds a payment of `amount` from `from` to `to`
+    pub fn assert_payment_event(env: &Env, contract: &Address, from: &Address, to: &Address, amount: i128) {
+        let found = payment_events(env, contract).iter()
+            .any(|(_, payment)| payment.from == *from && payment.to == *to && payment.amount == amount);
+        assert!(found, "no payment event for the given from, to and amount");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use soroban_sdk::vec;
+    use super::*;
+    use soroban_sdk::testutils::{Address as TestAddress, AuthorizedFunction, Events, Ledger, LedgerInfo};
+
+    const INITIAL_MINT_AMOUNT: i128 = 1_000_000_000;
+
+    use soroban_sdk::{Env, Address};
+
+    fn create_token_contract(env: &Env) -> Address {
+        let contract_address = testutils::register_token(env);
+        println!("Token contract created: {:?}", contract_address); // Debug print
+        contract_address
+    }
+
+    fn fund(env: &Env, token_id: &Address, to: &Address) {
+        testutils::fund(env, token_id, to, INITIAL_MINT_AMOUNT);
+    }
+
+    // Pool that converts at a fixed rate of 2 units of `token_in` per unit of `token_out`
+    #[contract]
+    pub struct MockPool;
+
+    #[contractimpl]
+    impl MockPool {
+        pub fn swap_exact_out(env: Env, from: Address, to: Address, token_in: Address, token_out: Address, amount_out: i128, max_amount_in: i128) -> i128 {
+            let amount_in = amount_out * 2;
+            assert!(amount_in <= max_amount_in);
+            TokenClient::new(&env, &token_in).transfer(&from, &env.current_contract_address(), &amount_in);
+            TokenClient::new(&env, &token_out).transfer(&env.current_contract_address(), &to, &amount_out);
+            amount_in
+        }
+
+        pub fn swap_exact_in(env: Env, from: Address, to: Address, token_in: Address, token_out: Address, amount_in: i128, min_amount_out: i128) -> i128 {
+            let amount_out = amount_in / 2;
+            assert!(amount_out >= min_amount_out);
+            TokenClient::new(&env, &token_in).transfer(&from, &env.current_contract_address(), &amount_in);
+            TokenClient::new(&env, &token_out).transfer(&env.current_contract_address(), &to, &amount_out);
+            amount_out
+        }
+    }
+
+    // Oracle quoting every asset at a fixed price, last updated at timestamp 0
+    #[contract]
+    pub struct MockOracle;
+
+    #[contractimpl]
+    impl MockOracle {
+        pub fn lastprice(_env: Env, _asset: Address) -> Option<PriceData> {
+            Some(PriceData { price: 2_0000000, timestamp: 0 })
+        }
+    }
+
+    // Milestone tracker whose milestones are marked done by anyone
+    #[contract]
+    pub struct MockMilestones;
+
+    #[contractimpl]
+    impl MockMilestones {
+        pub fn complete(env: Env, milestone: u32) {
+            env.storage().persistent().set(&milestone, &true);
+        }
+
+        pub fn is_done(env: Env, milestone: u32) -> bool {
+            env.storage().persistent().has(&milestone)
+        }
+    }
+
+    fn setup_test_env<'a>() -> (Env, PaymentMessagingSystemClient<'a>, Address) {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, token_id) = testutils::register_with_token(&env);
+        (env, client, token_id)
+    }
+
+    #[test]
+    fn test_transfer() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+        env.mock_all_auths();
+        let result = client.transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Test payment"), &None);
+        assert!(result);
+
+        env.mock_all_auths();
+        let balance = client.balance(&token_id, &recipient);
+        println!("Recipient balance after transfer: {:?}", balance); // Debug print
+        assert_eq!(balance, 10i128);
+    }
+
+    #[test]
+    fn test_recurring_payment() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+        env.mock_all_auths();
+        client.create_recurring_payment(&sender, &recipient, &10i128, &86400u64, &String::from_str(&env, "Daily payment"));
+        println!("Recurring payment created from {:?} to {:?}", sender, recipient); // Debug print
+
+        env.ledger().set(LedgerInfo {
+            timestamp: 100000,
+            protocol_version: 20,
+            sequence_number: 123,
+            network_id: Default::default(),
+            base_reserve: 10,
+            min_temp_entry_ttl: 10,
+            min_persistent_entry_ttl: 10,
+            max_entry_ttl: 3110400,
+        });
+
+        client.process_recurring_payments(&token_id);
+
+        env.mock_all_auths();
+        let history = client.get_transaction_history(&sender);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get(0).unwrap().amount, 10i128);
+        assert_eq!(history.get(0).unwrap().message, Memo::Text(String::from_str(&env, "Daily payment")));
+        println!("Transaction history for sender: {:?}", history); // Debug print
+    }
+
+    #[test]
+    fn test_multi_transfer() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        let user2 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user3 = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+        // Construct the recipients vector
+        let recipients = vec![
+            &env,
+            (user2.clone(), 10i128),
+            (user3.clone(), 20i128),
+        ];
+
+        env.mock_all_auths();
+        let result = client.multi_transfer(&token_id, &sender, &recipients, &Memo::Text(String::from_str(&env, "Multi transfer")), &None);
+        assert!(result);
+
+        env.mock_all_auths();
+        let history = client.get_transaction_history(&sender);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0).unwrap().amount, 10i128);
+        assert_eq!(history.get(1).unwrap().amount, 20i128);
+        println!("Transaction history for sender after multi-transfer: {:?}", history); // Debug print
+    }
+
+    #[test]
+    fn test_transfer_on_behalf() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let spender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        TokenClient::new(&env, &token_id).approve(&owner, &client.address, &100i128, &1000u32);
+        client.authorize_spender(&owner, &spender, &token_id, &50i128, &1000u64);
+
+        client.transfer_on_behalf(&owner, &spender, &recipient, &30i128, &Memo::Text(String::from_str(&env, "Team lunch")));
+        assert_eq!(client.balance(&token_id, &recipient), 30i128);
+        assert!(client.try_transfer_on_behalf(&owner, &spender, &recipient, &30i128, &Memo::Text(String::from_str(&env, "Over limit"))).is_err());
+
+        let history = client.get_transaction_history(&owner);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get(0).unwrap().amount, 30i128);
+
+        client.revoke_spender(&owner, &spender);
+        assert!(client.try_transfer_on_behalf(&owner, &spender, &recipient, &10i128, &Memo::Text(String::from_str(&env, "Revoked"))).is_err());
+    }
+
+    #[test]
+    fn test_payment_link() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let secret = Bytes::from_slice(&env, b"open sesame");
+        let secret_hash: BytesN<32> = env.crypto().sha256(&secret).into();
+        let link_id = client.create_payment_link(&sender, &token_id, &25i128, &secret_hash, &1000u64);
+
+        let wrong = Bytes::from_slice(&env, b"wrong");
+        assert!(client.try_claim_link(&link_id, &wrong, &recipient).is_err());
+
+        assert!(client.claim_link(&link_id, &secret, &recipient));
+        assert_eq!(client.balance(&token_id, &recipient), 25i128);
+        assert!(client.try_claim_link(&link_id, &secret, &recipient).is_err());
+        assert_eq!(client.get_transaction_history(&sender).len(), 1);
+    }
+
+    #[test]
+    fn test_htlc() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let receiver = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let preimage = Bytes::from_slice(&env, b"swap secret");
+        let hashlock: BytesN<32> = env.crypto().sha256(&preimage).into();
+
+        let withdrawn = client.create_htlc(&sender, &receiver, &token_id, &40i128, &hashlock, &1000u64);
+        assert!(client.try_refund_htlc(&withdrawn).is_err());
+        assert!(client.withdraw_htlc(&withdrawn, &preimage));
+        assert_eq!(client.balance(&token_id, &receiver), 40i128);
+        assert_eq!(client.get_htlc(&withdrawn).preimage, Some(preimage.clone()));
+
+        let refunded = client.create_htlc(&sender, &receiver, &token_id, &15i128, &hashlock, &1000u64);
+        env.ledger().with_mut(|li| li.timestamp = 1000);
+        assert!(client.try_withdraw_htlc(&refunded, &preimage).is_err());
+        client.refund_htlc(&refunded);
+        assert_eq!(client.balance(&token_id, &sender), INITIAL_MINT_AMOUNT - 40i128);
+    }
+
+    #[test]
+    fn test_campaign() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let donor = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &donor);
+
+        let funded = client.create_campaign(&owner, &token_id, &50i128, &1000u64, &String::from_str(&env, "New roof"));
+        client.donate(&funded, &donor, &50i128, &String::from_str(&env, "Good luck"));
+        assert_eq!(client.get_donations(&funded).get(0).unwrap().message, String::from_str(&env, "Good luck"));
+        assert_eq!(client.withdraw_campaign(&funded), 50i128);
+        assert_eq!(client.balance(&token_id, &owner), 50i128);
+        assert!(client.try_donate(&funded, &donor, &10i128, &String::from_str(&env, "Late")).is_err());
+
+        let missed = client.create_campaign(&owner, &token_id, &500i128, &1000u64, &String::from_str(&env, "Bus"));
+        client.donate(&missed, &donor, &20i128, &String::from_str(&env, "Partial"));
+        assert!(client.try_withdraw_campaign(&missed).is_err());
+        assert!(client.try_refund_donation(&missed, &donor).is_err());
+
+        env.ledger().with_mut(|li| li.timestamp = 1001);
+        assert_eq!(client.refund_donation(&missed, &donor), 20i128);
+        assert_eq!(client.balance(&token_id, &donor), INITIAL_MINT_AMOUNT - 50i128);
+    }
+
+    #[test]
+    fn test_payroll() {
+        let (env, client, token_id) = setup_test_env();
+        let employer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let alice = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let bob = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &employer);
+
+        let employees = vec![&env, (alice.clone(), 100i128), (bob.clone(), 200i128)];
+        let payroll_id = client.create_payroll(&employer, &token_id, &employees, &86400u64);
+        assert!(client.try_run_payroll(&payroll_id).is_err());
+
+        env.ledger().with_mut(|li| li.timestamp = 86400);
+        let batch_id = client.run_payroll(&payroll_id);
+        assert_eq!(client.balance(&token_id, &alice), 100i128);
+        assert_eq!(client.balance(&token_id, &bob), 200i128);
+
+        let history = client.get_transaction_history(&employer);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0).unwrap().batch_id, Some(batch_id));
+        assert_eq!(history.get(1).unwrap().batch_id, Some(batch_id));
+    }
+
+    #[test]
+    fn test_transfer_idempotency_key() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let key = Some(TransferOptions {
+            idempotency_key: Some(Bytes::from_array(&env, &[7u8; 32])),
+            metadata: None,
+            attachments: vec![&env],
+        });
+        let message = String::from_str(&env, "Invoice 42");
+        assert!(client.transfer(&token_id, &sender, &recipient, &10i128, &message, &key));
+        assert!(client.try_transfer(&token_id, &sender, &recipient, &10i128, &message, &key).is_err());
+
+        let recipients = vec![&env, (recipient.clone(), 5i128)];
+        assert!(client.try_multi_transfer(&token_id, &sender, &recipients, &Memo::Text(message), &Some(BytesN::from_array(&env, &[7u8; 32]))).is_err());
+        assert_eq!(client.balance(&token_id, &recipient), 10i128);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        client.initialize(&admin);
+        client.set_rate_limit(&2u32, &10u32);
+
+        let message = String::from_str(&env, "Spam");
+        client.transfer(&token_id, &sender, &recipient, &1i128, &message, &None);
+        client.transfer(&token_id, &sender, &recipient, &1i128, &message, &None);
+        assert!(client.try_transfer(&token_id, &sender, &recipient, &1i128, &message, &None).is_err());
+        assert_eq!(client.get_rate_limit_status(&sender).unwrap().used, 2u32);
+
+        env.ledger().with_mut(|li| li.sequence_number += 10);
+        assert_eq!(client.get_rate_limit_status(&sender).unwrap().used, 0u32);
+        client.transfer(&token_id, &sender, &recipient, &1i128, &message, &None);
+    }
+
+    #[test]
+    fn test_balance_of() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let holder = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &holder);
+
+        env.set_auths(&[]);
+        assert_eq!(client.balance_of(&token_id, &holder), INITIAL_MINT_AMOUNT);
+        assert!(client.try_balance(&token_id, &holder).is_err());
+
+        env.mock_all_auths();
+        client.initialize(&admin);
+        client.set_private_balances(&true);
+        env.set_auths(&[]);
+        assert!(client.try_balance_of(&token_id, &holder).is_err());
+    }
+
+    #[test]
+    fn test_get_balances() {
+        let (env, client, token_id) = setup_test_env();
+        let other_token = create_token_contract(&env);
+        let holder = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &holder);
+
+        let balances = client.get_balances(&holder, &vec![&env, token_id.clone(), other_token.clone()]);
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances.get(token_id).unwrap(), INITIAL_MINT_AMOUNT);
+        assert_eq!(balances.get(other_token).unwrap(), 0i128);
+    }
+
+    #[test]
+    fn test_recurring_payment_with_payout_token() {
+        let (env, client, token_id) = setup_test_env();
+        let usd_token = create_token_contract(&env);
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let pool = env.register_contract(None, MockPool);
+        fund(&env, &usd_token, &pool);
+        client.initialize(&admin);
+        client.set_liquidity_pool(&pool);
+
+        client.create_recurring_payment(&sender, &merchant, &10i128, &100u64, &String::from_str(&env, "Subscription"));
+        client.set_recurring_payout_token(&sender, &usd_token, &25i128);
+
+        env.ledger().with_mut(|li| li.timestamp = 100);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&usd_token, &merchant), 10i128);
+        assert_eq!(client.balance(&token_id, &sender), INITIAL_MINT_AMOUNT - 20i128);
+
+        // A Bps keeper reward is a share of the 20 units of the processing token charged,
+        // not of the 10 units paid out
+        let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+        client.set_keeper_reward(&sender, &KeeperReward::Bps(1000));
+        env.ledger().with_mut(|li| li.timestamp = 200);
+        assert!(client.process_recurring_for(&keeper, &sender, &token_id));
+        assert_eq!(client.balance(&usd_token, &merchant), 20i128);
+        assert_eq!(client.balance(&token_id, &keeper), 2i128);
+    }
+
+    #[test]
+    fn test_escrow_with_payout_token() {
+        let (env, client, token_id) = setup_test_env();
+        let usd_token = create_token_contract(&env);
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let friend = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let pool = env.register_contract(None, MockPool);
+        fund(&env, &usd_token, &pool);
+        client.initialize(&admin);
+        client.set_liquidity_pool(&pool);
+
+        assert!(client.try_set_escrow_payout_token(&sender, &token_id, &token_id, &5000u32).is_err());
+        client.set_escrow_payout_token(&sender, &token_id, &usd_token, &4000u32);
+        let preimage = Bytes::from_slice(&env, b"secret");
+        let secret_hash: BytesN<32> = env.crypto().sha256(&preimage).into();
+        let link_id = client.create_payment_link(&sender, &token_id, &40i128, &secret_hash, &1000u64);
+        client.clear_escrow_payout_token(&sender, &token_id);
+        let plain_link_id = client.create_payment_link(&sender, &token_id, &10i128, &secret_hash, &1000u64);
+
+        // Claiming needs no signature: the contract authorizes the pool's pull itself
+        env.set_auths(&[]);
+        client.claim_link(&link_id, &preimage, &friend);
+        client.claim_link(&plain_link_id, &preimage, &friend);
+        env.mock_all_auths();
+        assert_eq!(client.balance(&usd_token, &friend), 20i128);
+        assert_eq!(client.balance(&token_id, &pool), 40i128);
+        assert_eq!(client.balance(&token_id, &friend), 10i128);
+
+        // The swap must return at least the escrow's minimum payout
+        client.set_escrow_payout_token(&sender, &token_id, &usd_token, &6000u32);
+        let strict_link_id = client.create_payment_link(&sender, &token_id, &40i128, &secret_hash, &1000u64);
+        assert!(client.try_claim_link(&strict_link_id, &preimage, &friend).is_err());
+    }
+
+    #[test]
+    fn test_transfer_metadata() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let mut metadata = Map::new(&env);
+        metadata.set(symbol_short!("order_id"), String::from_str(&env, "A-1001"));
+        client.transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Order"), &Some(TransferOptions { idempotency_key: None, metadata: Some(metadata.clone()), attachments: vec![&env] }));
+
+        let payment = client.get_transaction_history(&sender).get(0).unwrap();
+        assert_eq!(payment.metadata, Some(metadata.clone()));
+
+        let mut oversized = Map::new(&env);
+        for key in ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"] {
+            oversized.set(Symbol::new(&env, key), String::from_str(&env, "x"));
+        }
+        assert!(client.try_transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Order"), &Some(TransferOptions { idempotency_key: None, metadata: Some(oversized), attachments: vec![&env] })).is_err());
+
+        let mut long_value = Map::new(&env);
+        long_value.set(symbol_short!("note"), String::from_bytes(&env, &[b'x'; 129]));
+        assert!(client.try_transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Order"), &Some(TransferOptions { idempotency_key: None, metadata: Some(long_value.clone()), attachments: vec![&env] })).is_err());
+
+        // Request metadata is bounded the same way and carried onto the settling payment
+        let options = |metadata| Some(RequestOptions { due_date: None, grace_period: 0, late_fee_bps: 0, metadata: Some(metadata) });
+        assert!(client.try_request_payment(&recipient, &sender, &token_id, &20i128, &String::from_str(&env, "Invoice"), &options(long_value)).is_err());
+        let request_id = client.request_payment(&recipient, &sender, &token_id, &20i128, &String::from_str(&env, "Invoice"), &options(metadata.clone()));
+        assert_eq!(client.get_payment_request(&request_id).metadata, Some(metadata.clone()));
+        client.pay_request_partial(&sender, &request_id, &20i128);
+        assert_eq!(client.get_transaction_history(&sender).get(1).unwrap().metadata, Some(metadata));
+    }
+
+    #[test]
+    fn test_get_payment() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user2 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user3 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let recipients = vec![&env, (user2.clone(), 10i128), (user3.clone(), 20i128)];
+        client.multi_transfer(&token_id, &sender, &recipients, &Memo::Text(String::from_str(&env, "Split")), &None);
+
+        env.set_auths(&[]);
+        assert_eq!(client.get_payment(&0u64).to, user2);
+        assert_eq!(client.get_payment(&1u64).amount, 20i128);
+        assert!(client.try_get_payment(&2u64).is_err());
+    }
+
+    #[test]
+    fn test_deposit_funded_payments() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        assert_eq!(client.deposit(&owner, &token_id, &100i128), 100i128);
+        client.transfer_from_deposit(&token_id, &owner, &recipient, &30i128, &Memo::Text(String::from_str(&env, "From vault")));
+        assert_eq!(client.get_deposit(&owner, &token_id), 70i128);
+
+        client.create_recurring_payment(&owner, &recipient, &20i128, &100u64, &String::from_str(&env, "Rent"));
+        client.set_recurring_use_deposit(&owner, &Some(token_id.clone()));
+
+        // Processing a deposit-funded plan needs no signature from the owner
+        env.set_auths(&[]);
+        env.ledger().with_mut(|li| li.timestamp = 100);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.get_deposit(&owner, &token_id), 50i128);
+        assert_eq!(client.balance_of(&token_id, &recipient), 50i128);
+
+        // Nobody can charge the plan in another token the owner has deposited
+        env.mock_all_auths();
+        let other_token = create_token_contract(&env);
+        testutils::fund(&env, &other_token, &owner, 100i128);
+        client.deposit(&owner, &other_token, &100i128);
+        env.set_auths(&[]);
+        env.ledger().with_mut(|li| li.timestamp = 200);
+        client.process_recurring_payments(&other_token);
+        assert!(client.try_process_recurring_batch(&other_token, &None, &10u32).is_ok());
+        env.mock_all_auths();
+        assert_eq!(client.get_deposit(&owner, &other_token), 100i128);
+        assert!(client.try_process_recurring_for(&recipient, &owner, &other_token).is_err());
+
+        assert!(client.try_withdraw(&owner, &token_id, &51i128).is_err());
+        assert_eq!(client.withdraw(&owner, &token_id, &50i128), 0i128);
+        assert_eq!(client.balance(&token_id, &owner), INITIAL_MINT_AMOUNT - 50i128);
+    }
+
+    #[test]
+    fn test_deposit_funded_escrows() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let friend = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        client.deposit(&owner, &token_id, &100i128);
+        client.set_escrow_use_deposit(&owner, &true);
+        let secret_hash: BytesN<32> = env.crypto().sha256(&Bytes::from_slice(&env, b"secret")).into();
+        client.create_payment_link(&owner, &token_id, &40i128, &secret_hash, &1000u64);
+        client.send_gift(&owner, &friend, &token_id, &50i128, &0u64, &String::from_str(&env, "Happy birthday"));
+        assert_eq!(client.get_deposit(&owner, &token_id), 10i128);
+        assert_eq!(client.balance(&token_id, &owner), INITIAL_MINT_AMOUNT - 100i128);
+        assert!(client.try_create_htlc(&owner, &friend, &token_id, &11i128, &secret_hash, &1000u64).is_err());
+
+        client.set_escrow_use_deposit(&owner, &false);
+        client.create_htlc(&owner, &friend, &token_id, &11i128, &secret_hash, &1000u64);
+        assert_eq!(client.get_deposit(&owner, &token_id), 10i128);
+        assert_eq!(client.balance(&token_id, &owner), INITIAL_MINT_AMOUNT - 111i128);
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let heir1 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let heir2 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        client.deposit(&owner, &token_id, &1000i128);
+        let beneficiaries = vec![&env, (heir1.clone(), 2500u32), (heir2.clone(), 7500u32)];
+        let wrapping = vec![&env, (heir1.clone(), u32::MAX), (heir2.clone(), 10_001u32)];
+        assert!(client.try_configure_inheritance(&owner, &wrapping, &1000u64).is_err());
+        client.configure_inheritance(&owner, &beneficiaries, &1000u64);
+
+        env.ledger().with_mut(|li| li.timestamp = 900);
+        client.withdraw(&owner, &token_id, &100i128);
+        env.ledger().with_mut(|li| li.timestamp = 1500);
+        assert!(client.try_trigger_inheritance(&owner, &token_id).is_err());
+
+        // Any call the owner signs resets the timer, not just transfers
+        env.ledger().with_mut(|li| li.timestamp = 1800);
+        client.send_message(&owner, &heir1, &String::from_str(&env, "Still here"), &false, &vec![&env]);
+        env.ledger().with_mut(|li| li.timestamp = 2700);
+        assert!(client.try_trigger_inheritance(&owner, &token_id).is_err());
+
+        env.ledger().with_mut(|li| li.timestamp = 2900);
+        env.set_auths(&[]);
+        assert_eq!(client.trigger_inheritance(&owner, &token_id), 900i128);
+        assert_eq!(client.balance_of(&token_id, &heir1), 225i128);
+        assert_eq!(client.balance_of(&token_id, &heir2), 675i128);
+    }
+
+    #[test]
+    fn test_session_send_message() {
+        let (env, client, _) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let chat = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let payments = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let friend = <soroban_sdk::Address as TestAddress>::generate(&env);
+        client.create_session_key(&owner, &chat, &vec![&env, symbol_short!("send_msg")], &1000u64, &0i128);
+        client.create_session_key(&owner, &payments, &vec![&env, symbol_short!("transfer")], &1000u64, &20i128);
+
+        let text = String::from_str(&env, "Sent from my phone");
+        client.session_send_message(&chat, &owner, &friend, &text, &false, &vec![&env]);
+        let inbox = client.get_inbox(&friend);
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox.get(0).unwrap().from, owner);
+        assert_eq!(inbox.get(0).unwrap().text, text);
+
+        assert!(client.try_session_send_message(&payments, &owner, &friend, &text, &false, &vec![&env]).is_err());
+        env.ledger().with_mut(|li| li.timestamp = 1001);
+        assert!(client.try_session_send_message(&chat, &owner, &friend, &text, &false, &vec![&env]).is_err());
+    }
+
+    #[test]
+    fn test_session_transfer() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let session = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+        client.deposit(&owner, &token_id, &100i128);
+
+        let ops = vec![&env, symbol_short!("transfer")];
+        client.create_session_key(&owner, &session, &ops, &1000u64, &20i128);
+
+        let message = Memo::Text(String::from_str(&env, "Coffee"));
+        client.session_transfer(&session, &owner, &token_id, &recipient, &15i128, &message);
+        assert!(client.try_session_transfer(&session, &owner, &token_id, &recipient, &25i128, &message).is_err());
+        assert_eq!(client.get_deposit(&owner, &token_id), 85i128);
+
+        env.ledger().with_mut(|li| li.timestamp = 1001);
+        assert!(client.try_session_transfer(&session, &owner, &token_id, &recipient, &5i128, &message).is_err());
+    }
+
+    #[test]
+    fn test_pay_request_partial() {
+        let (env, client, token_id) = setup_test_env();
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let customer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &customer);
+
+        let request_id = client.request_payment(&merchant, &customer, &token_id, &100i128, &String::from_str(&env, "Sofa"), &None);
+        assert_eq!(client.pay_request_partial(&customer, &request_id, &30i128), 70i128);
+        assert!(!client.get_payment_request(&request_id).fulfilled);
+        assert!(client.try_pay_request_partial(&customer, &request_id, &80i128).is_err());
+
+        assert_eq!(client.pay_request_partial(&customer, &request_id, &70i128), 0i128);
+        assert!(client.get_payment_request(&request_id).fulfilled);
+        assert_eq!(client.balance(&token_id, &merchant), 100i128);
+        assert_eq!(client.get_transaction_history(&customer).len(), 2);
+    }
+
+    #[test]
+    fn test_request_late_fee() {
+        let (env, client, token_id) = setup_test_env();
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let customer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &customer);
+
+        let options = RequestOptions { due_date: Some(1000), grace_period: 100, late_fee_bps: 500, metadata: None };
+        let request_id = client.request_payment(&merchant, &customer, &token_id, &1000i128, &String::from_str(&env, "Rent"), &Some(options));
+
+        client.pay_request_partial(&customer, &request_id, &400i128);
+        env.ledger().with_mut(|li| li.timestamp = 1100);
+        assert_eq!(client.get_amount_due(&request_id), 600i128);
+
+        env.ledger().with_mut(|li| li.timestamp = 1101);
+        assert_eq!(client.get_amount_due(&request_id), 630i128);
+        client.pay_request_partial(&customer, &request_id, &600i128);
+        assert!(client.get_payment_request(&request_id).fulfilled);
+        assert_eq!(client.balance(&token_id, &merchant), 1030i128);
+    }
+
+    #[test]
+    fn test_reputation() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let receiver = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let preimage = Bytes::from_slice(&env, b"secret");
+        let hashlock: BytesN<32> = env.crypto().sha256(&preimage).into();
+        let htlc_id = client.create_htlc(&sender, &receiver, &token_id, &10i128, &hashlock, &1000u64);
+        client.withdraw_htlc(&htlc_id, &preimage);
+
+        client.create_recurring_payment(&sender, &receiver, &5i128, &100u64, &String::from_str(&env, "Weekly"));
+        env.ledger().with_mut(|li| li.timestamp = 100);
+        client.process_recurring_payments(&token_id);
+
+        let score = client.get_reputation(&sender);
+        assert_eq!(score.completed_escrows, 1);
+        assert_eq!(score.on_time_recurring, 1);
+        assert_eq!(client.get_reputation(&receiver).completed_escrows, 1);
+    }
+
+    #[test]
+    fn test_events_history_mode() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        client.initialize(&admin);
+        client.set_history_mode(&HistoryMode::Events);
+        client.transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Indexed"), &None);
+        let payment_events = env.events().all().iter()
+            .filter(|(contract, _, _)| *contract == client.address)
+            .count();
+        assert_eq!(payment_events, 1);
+
+        assert_eq!(client.get_transaction_history(&sender).len(), 0);
+        assert!(client.try_get_payment(&0u64).is_err());
+    }
+
+    #[test]
+    fn test_history_visibility() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        client.transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Rent"), &None);
+
+        env.set_auths(&[]);
+        assert!(client.try_get_transaction_history(&sender).is_err());
+        assert_eq!(client.get_payment(&0u64).to, recipient);
+
+        env.mock_all_auths();
+        client.set_history_visibility(&sender, &HistoryVisibility::Public);
+        env.set_auths(&[]);
+        assert_eq!(client.get_transaction_history(&sender).len(), 1);
+    }
+
+    #[test]
+    fn test_broadcast_message() {
+        let (env, client, _) = setup_test_env();
+        let manager = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user2 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user3 = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+        let text = String::from_str(&env, "Meetup on Friday");
+        client.broadcast_message(&manager, &vec![&env, user2.clone(), user3.clone()], &text, &vec![&env]);
+
+        let inbox = client.get_inbox(&user2);
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox.get(0).unwrap().text, text);
+        assert_eq!(client.get_inbox(&user3).get(0).unwrap().from, manager);
+    }
+
+    #[test]
+    fn test_recurring_payment_failures_suspend_plan() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let broke = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        client.create_recurring_payment(&sender, &recipient, &10i128, &100u64, &String::from_str(&env, "Funded"));
+        client.create_recurring_payment(&broke, &recipient, &10i128, &100u64, &String::from_str(&env, "Unfunded"));
+
+        // Retrying within the same cycle does not count as another failure
+        for attempt in 1..=MAX_FAILED_ATTEMPTS as u64 {
+            env.ledger().with_mut(|li| li.timestamp = attempt * 100);
+            client.process_recurring_payments(&token_id);
+            client.process_recurring_payments(&token_id);
+            assert!(client.try_process_recurring_for(&recipient, &broke, &token_id).is_err());
+        }
+        assert_eq!(client.balance(&token_id, &recipient), 30i128);
+
+        // The unfunded plan is now suspended and skipped even once funded
+        fund(&env, &token_id, &broke);
+        env.ledger().with_mut(|li| li.timestamp = 400);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &broke), INITIAL_MINT_AMOUNT);
+
+        client.reactivate_plan(&broke);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &broke), INITIAL_MINT_AMOUNT - 10i128);
+    }
+
+    #[test]
+    fn test_process_recurring_for_pays_keeper() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        client.deposit(&owner, &token_id, &1000i128);
+        client.create_recurring_payment(&owner, &recipient, &200i128, &100u64, &String::from_str(&env, "Salary"));
+        client.set_recurring_use_deposit(&owner, &Some(token_id.clone()));
+        client.set_keeper_reward(&owner, &KeeperReward::Bps(50));
+
+        assert!(client.try_process_recurring_for(&keeper, &owner, &token_id).is_err());
+        env.ledger().with_mut(|li| li.timestamp = 100);
+        assert!(client.process_recurring_for(&keeper, &owner, &token_id));
+        assert_eq!(client.balance(&token_id, &recipient), 200i128);
+        assert_eq!(client.balance(&token_id, &keeper), 1i128);
+        assert_eq!(client.get_deposit(&owner, &token_id), 799i128);
+    }
+
+    #[test]
+    fn test_confirmation_threshold() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        client.set_confirmation_threshold(&sender, &token_id, &100i128, &3600u64);
+
+        let message = String::from_str(&env, "Car");
+        assert!(client.transfer(&token_id, &sender, &recipient, &100i128, &message, &None));
+        assert!(!client.transfer(&token_id, &sender, &recipient, &5000i128, &message, &None));
+        assert_eq!(client.balance(&token_id, &recipient), 100i128);
+        assert_eq!(client.get_pending_intents(&sender), vec![&env, 0u64]);
+
+        assert!(client.try_confirm_transfer(&0u64).is_err());
+        env.ledger().with_mut(|li| li.timestamp = 3600);
+        assert!(client.confirm_transfer(&0u64));
+        assert_eq!(client.balance(&token_id, &recipient), 5100i128);
+        assert_eq!(client.get_pending_intents(&sender).len(), 0);
+
+        assert!(!client.transfer(&token_id, &sender, &recipient, &5000i128, &message, &None));
+        assert_eq!(client.get_pending_intents(&sender), vec![&env, 1u64]);
+        client.cancel_transfer(&1u64);
+        assert!(client.try_get_transfer_intent(&1u64).is_err());
+        assert_eq!(client.get_pending_intents(&sender).len(), 0);
+
+        // Paths that cannot hold a payment reject large amounts instead of sending them
+        let recipients = vec![&env, (recipient.clone(), 450i128), (recipient.clone(), 450i128)];
+        assert!(client.try_multi_transfer(&token_id, &sender, &recipients, &Memo::Text(message.clone()), &None).is_err());
+        client.deposit(&sender, &token_id, &1000i128);
+        assert!(client.try_transfer_from_deposit(&token_id, &sender, &recipient, &900i128, &Memo::None).is_err());
+        assert_eq!(client.balance(&token_id, &recipient), 5100i128);
+    }
+
+    #[test]
+    fn test_gift() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let message = String::from_str(&env, "Happy birthday!");
+        let gift_id = client.send_gift(&sender, &recipient, &token_id, &50i128, &5000u64, &message);
+        assert!(client.try_get_gift_message(&gift_id).is_err());
+        assert!(client.try_claim_gift(&gift_id).is_err());
+
+        env.ledger().with_mut(|li| li.timestamp = 5000);
+        assert_eq!(client.get_gift_message(&gift_id), message);
+        assert_eq!(client.claim_gift(&gift_id), message);
+        assert_eq!(client.balance(&token_id, &recipient), 50i128);
+        assert!(client.try_claim_gift(&gift_id).is_err());
+    }
+
+    #[test]
+    fn test_transfer_roundup() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let charity = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        client.set_roundup(&sender, &charity, &100i128);
+
+        let message = String::from_str(&env, "Groceries");
+        client.transfer(&token_id, &sender, &recipient, &1234i128, &message, &None);
+        client.transfer(&token_id, &sender, &recipient, &500i128, &message, &None);
+        assert_eq!(client.balance(&token_id, &recipient), 1734i128);
+        assert_eq!(client.balance(&token_id, &charity), 66i128);
+
+        let history = client.get_transaction_history(&sender);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.get(0).unwrap().to, charity);
+        assert_eq!(history.get(0).unwrap().batch_id, history.get(1).unwrap().batch_id);
+        assert_eq!(history.get(2).unwrap().batch_id, None);
+    }
+
+    #[test]
+    fn test_request_payment_usd() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let customer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &customer);
+
+        let oracle = env.register_contract(None, MockOracle);
+        client.initialize(&admin);
+        client.set_oracle(&oracle, &600u64);
+
+        // $100 at $2 per token is 50 tokens (7 decimals)
+        let request_id = client.request_payment_usd(&merchant, &customer, &100_0000000i128, &token_id);
+        client.pay_request_partial(&customer, &request_id, &40_0000000i128);
+        assert_eq!(client.balance(&token_id, &merchant), 20_0000000i128);
+
+        env.ledger().with_mut(|li| li.timestamp = 601);
+        assert!(client.try_pay_request_partial(&customer, &request_id, &60_0000000i128).is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        let (env, client, _) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        assert!(client.get_profile(&owner).is_none());
+
+        let avatar_hash = BytesN::from_array(&env, &[1u8; 32]);
+        client.set_profile(&owner, &String::from_str(&env, "Alice"), &avatar_hash, &String::from_str(&env, "Coffee shop owner"));
+        let profile = client.get_profile(&owner).unwrap();
+        assert_eq!(profile.display_name, String::from_str(&env, "Alice"));
+        assert_eq!(profile.avatar_hash, avatar_hash);
+
+        let long_name = String::from_str(&env, "abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxyzabcdefghijklm");
+        assert!(client.try_set_profile(&owner, &long_name, &avatar_hash, &String::from_str(&env, "")).is_err());
+    }
+
+    #[test]
+    fn test_subscription() {
+        let (env, client, token_id) = setup_test_env();
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let subscriber = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &subscriber);
+        client.deposit(&subscriber, &token_id, &100i128);
+
+        let subscription_id = client.propose_subscription(&merchant, &subscriber, &token_id, &30i128, &1000u64);
+        assert!(client.try_charge_subscription(&subscription_id).is_err());
+
+        client.accept_subscription(&subscription_id);
+        client.charge_subscription(&subscription_id);
+        assert!(client.try_charge_subscription(&subscription_id).is_err());
+        env.ledger().with_mut(|li| li.timestamp = 1000);
+        client.charge_subscription(&subscription_id);
+        assert_eq!(client.balance(&token_id, &merchant), 60i128);
+        assert_eq!(client.get_deposit(&subscriber, &token_id), 40i128);
+
+        client.cancel_subscription(&subscription_id, &subscriber);
+        env.ledger().with_mut(|li| li.timestamp = 2000);
+        assert!(client.try_charge_subscription(&subscription_id).is_err());
+    }
+
+    #[test]
+    fn test_process_recurring_batch() {
+        let (env, client, token_id) = setup_test_env();
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        for _ in 0..5 {
+            let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+            fund(&env, &token_id, &sender);
+            client.create_recurring_payment(&sender, &recipient, &10i128, &100u64, &String::from_str(&env, "Plan"));
+        }
+
+        env.ledger().with_mut(|li| li.timestamp = 100);
+        assert!(client.try_process_recurring_batch(&token_id, &None, &0u32).is_err());
+        let mut cursor = None;
+        let mut batches = 0;
+        loop {
+            cursor = client.process_recurring_batch(&token_id, &cursor, &2u32);
+            batches += 1;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(batches, 3);
+        assert_eq!(client.balance(&token_id, &recipient), 50i128);
+    }
+
+    #[test]
+    fn test_ephemeral_message() {
+        let (env, client, _) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        client.initialize(&admin);
+        client.set_ephemeral_ttl(&100u32);
+
+        client.send_message(&sender, &recipient, &String::from_str(&env, "Kept"), &false, &vec![&env]);
+        client.send_message(&sender, &recipient, &String::from_str(&env, "Gone soon"), &true, &vec![&env]);
+        assert_eq!(client.get_inbox(&recipient).len(), 2);
+
+        env.ledger().with_mut(|li| li.sequence_number += 101);
+        let inbox = client.get_inbox(&recipient);
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox.get(0).unwrap().text, String::from_str(&env, "Kept"));
+    }
+
+    #[test]
+    fn test_export_import_history() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user2 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user3 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
 
-                // Update last payment time
-                payment.last_payment = current_timestamp;
-                recurring_payments.set(from.clone(), payment.clone());
+        let recipients = vec![&env, (user2.clone(), 10i128), (user3.clone(), 20i128)];
+        client.multi_transfer(&token_id, &sender, &recipients, &Memo::Text(String::from_str(&env, "Old")), &None);
+        let blob = client.export_history(&sender, &1u32, &10u32);
 
-                // Store payment record
-                let mut payments = Self::get_payments(&env, &from);
-                payments.push_back(Payment {
-                    from: from.clone(),
-                    to: payment.to.clone(),
-                    amount: payment.amount,
-                    message: payment.message.clone(),
-                });
-                Self::set_payments(&env, &from, &payments);
+        let new_deployment = PaymentMessagingSystemClient::new(&env, &env.register_contract(None, PaymentMessagingSystem));
+        assert_eq!(new_deployment.import_history(&sender, &blob), 1u32);
+        let history = new_deployment.get_transaction_history(&sender);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get(0).unwrap().to, user3);
+        assert!(new_deployment.is_imported(&0u64));
+        assert!(!client.is_imported(&1u64));
+
+        assert!(new_deployment.try_import_history(&user2, &blob).is_err());
+    }
+
+    #[test]
+    fn test_pay_from_contract() {
+        let (env, client, token_id) = setup_test_env();
+        let caller = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &caller);
+
+        let memo = Memo::Id(42);
+        let payment_id = client.pay_from_contract(&caller, &recipient, &token_id, &15i128, &memo);
 
-                println!("Processed recurring payment: From: {:?}, To: {:?}, Amount: {:?}", from, payment.to, payment.amount); // Debug print
+        let (authorizer, invocation) = env.auths().first().unwrap().clone();
+        assert_eq!(authorizer, caller);
+        match invocation.function {
+            AuthorizedFunction::Contract((_, function, args)) => {
+                assert_eq!(function, Symbol::new(&env, "pay_from_contract"));
+                assert_eq!(args, (recipient.clone(), token_id.clone(), 15i128, memo.clone()).into_val(&env));
             }
+            _ => panic!("unexpected auth"),
         }
 
-        Self::set_recurring_payments(&env, &recurring_payments);
+        client.set_history_visibility(&caller, &HistoryVisibility::Public);
+        assert_eq!(client.get_payment(&payment_id).message, memo);
+        assert_eq!(client.balance(&token_id, &recipient), 15i128);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use soroban_sdk::vec;
-    use super::*;
-    use soroban_sdk::testutils::{Address as TestAddress, Ledger, LedgerInfo};
+    #[test]
+    fn test_calendar_schedules() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let landlord = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
 
-    const INITIAL_MINT_AMOUNT: i128 = 1_000_000_000;
+        // 2024-01-31 12:00 UTC; a plan on the 31st falls on 2024-02-29, then 2024-03-31
+        env.ledger().with_mut(|li| li.timestamp = 1_706_702_400);
+        client.create_recurring_payment(&sender, &landlord, &100i128, &0u64, &String::from_str(&env, "Rent"));
+        client.set_recurring_schedule(&sender, &Schedule::Monthly(31));
 
-    use soroban_sdk::{Env, Address, String as SorobanString};
+        env.ledger().with_mut(|li| li.timestamp = 1_709_164_799);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &landlord), 0i128);
 
-    fn create_token_contract(env: &Env) -> Address {
-        let contract_id_str = String::from_str(env, "GA5DLODYBEZBKY3GCSVU42N6YARV4LCYGWIZVI5SSKFIAJTKYMFXB5DI");
-        let contract_address = Address::from_string(&contract_id_str);
-        env.register_stellar_asset_contract_v2(contract_address.clone());
-        let client = StellarAssetClient::new(env, &contract_address); // fixed to pass Address type
-        let recipient = <soroban_sdk::Address as TestAddress>::generate(env);
-        client.mint(&recipient, &INITIAL_MINT_AMOUNT);
-        println!("Token contract created: {:?}", contract_address); // Debug print
-        contract_address
+        env.ledger().with_mut(|li| li.timestamp = 1_709_164_800);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &landlord), 100i128);
+
+        env.ledger().with_mut(|li| li.timestamp = 1_711_843_200);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &landlord), 200i128);
+
+        // Weekly on Monday: the Monday after 2024-03-31 (a Sunday) is 2024-04-01
+        client.set_recurring_schedule(&sender, &Schedule::Weekly(0));
+        env.ledger().with_mut(|li| li.timestamp = 1_711_929_600);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &landlord), 300i128);
+        assert!(client.try_set_recurring_schedule(&sender, &Schedule::Weekly(7)).is_err());
     }
 
-    fn setup_test_env<'a>() -> (Env, PaymentMessagingSystemClient<'a>, Address) {
-        let env = Env::default();
-        let contract_id = env.register_contract(None, PaymentMessagingSystem);
-        let client = PaymentMessagingSystemClient::new(&env, &contract_id);
-        let token_id = create_token_contract(&env);
-        (env, client, token_id)
+    #[test]
+    fn test_default_token() {
+        let (env, client, token_id) = setup_test_env();
+        let other_token = create_token_contract(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        fund(&env, &other_token, &sender);
+
+        let message = String::from_str(&env, "Default");
+        assert!(client.try_transfer_default(&sender, &recipient, &10i128, &message).is_err());
+
+        client.set_default_token(&sender, &token_id);
+        client.transfer_default(&sender, &recipient, &10i128, &message);
+        assert_eq!(client.balance(&token_id, &recipient), 10i128);
+
+        client.set_default_token(&recipient, &other_token);
+        client.transfer_default(&sender, &recipient, &10i128, &message);
+        assert_eq!(client.balance(&other_token, &recipient), 10i128);
+
+        let request_id = client.request_payment_default(&recipient, &sender, &5i128, &message);
+        client.pay_request_partial(&sender, &request_id, &5i128);
+        assert_eq!(client.balance(&other_token, &recipient), 15i128);
     }
 
     #[test]
-    fn test_transfer() {
+    fn test_quote_transfer() {
         let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
         let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
         let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
 
-        env.mock_all_auths();
-        let result = client.transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Test payment"));
-        assert!(result);
+        let quote = client.quote_transfer(&sender, &recipient, &token_id, &100i128);
+        assert!(quote.allowed);
+        assert_eq!(quote.net_amount, 100i128);
+        assert_eq!(quote.reason, symbol_short!("ok"));
 
-        env.mock_all_auths();
-        let balance = client.balance(&token_id, &recipient);
-        println!("Recipient balance after transfer: {:?}", balance); // Debug print
-        assert_eq!(balance, 10i128);
+        assert_eq!(client.quote_transfer(&sender, &recipient, &token_id, &(INITIAL_MINT_AMOUNT + 1)).reason, symbol_short!("balance"));
+
+        // Self-transfers and zero amounts are rejected by the transfer just as the quote says
+        let message = String::from_str(&env, "Nothing");
+        assert_eq!(client.quote_transfer(&sender, &sender, &token_id, &10i128).reason, symbol_short!("invalid"));
+        assert!(client.try_transfer(&token_id, &sender, &sender, &10i128, &message, &None).is_err());
+        assert_eq!(client.quote_transfer(&sender, &recipient, &token_id, &0i128).reason, symbol_short!("invalid"));
+        assert!(client.try_transfer(&token_id, &sender, &recipient, &0i128, &message, &None).is_err());
+
+        client.set_confirmation_threshold(&sender, &token_id, &50i128, &60u64);
+        assert_eq!(client.quote_transfer(&sender, &recipient, &token_id, &100i128).reason, symbol_short!("confirm"));
+
+        client.initialize(&admin);
+        client.set_rate_limit(&0u32, &10u32);
+        assert!(!client.quote_transfer(&sender, &recipient, &token_id, &10i128).allowed);
     }
 
     #[test]
-    fn test_recurring_payment() {
+    fn test_airdrop() {
+        let (env, client, token_id) = setup_test_env();
+        let funder = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let alice = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let bob = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &funder);
+
+        let alice_leaf = PaymentMessagingSystem::airdrop_leaf(&env, &alice, 30i128);
+        let bob_leaf = PaymentMessagingSystem::airdrop_leaf(&env, &bob, 20i128);
+        let (first, second) = if alice_leaf < bob_leaf { (&alice_leaf, &bob_leaf) } else { (&bob_leaf, &alice_leaf) };
+        let mut pair = Bytes::from_array(&env, &first.to_array());
+        pair.extend_from_array(&second.to_array());
+        let root: BytesN<32> = env.crypto().sha256(&pair).into();
+
+        let airdrop_id = client.create_airdrop(&funder, &token_id, &60i128, &root, &1000u64);
+        assert!(client.try_claim_airdrop(&airdrop_id, &alice, &50i128, &vec![&env, bob_leaf.clone()]).is_err());
+        client.claim_airdrop(&airdrop_id, &alice, &30i128, &vec![&env, bob_leaf.clone()]);
+        assert_eq!(client.balance(&token_id, &alice), 30i128);
+        assert!(client.is_airdrop_claimed(&airdrop_id, &alice));
+        assert!(client.try_claim_airdrop(&airdrop_id, &alice, &30i128, &vec![&env, bob_leaf]).is_err());
+
+        env.ledger().with_mut(|li| li.timestamp = 1000);
+        assert!(client.try_claim_airdrop(&airdrop_id, &bob, &20i128, &vec![&env, alice_leaf]).is_err());
+        assert_eq!(client.reclaim_airdrop(&airdrop_id), 30i128);
+        assert_eq!(client.balance(&token_id, &funder), INITIAL_MINT_AMOUNT - 30i128);
+    }
+
+    #[test]
+    fn test_alert_threshold() {
         let (env, client, token_id) = setup_test_env();
         let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
         let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
 
-        env.mock_all_auths();
-        client.create_recurring_payment(&sender, &recipient, &10i128, &86400u64, &String::from_str(&env, "Daily payment"));
-        println!("Recurring payment created from {:?} to {:?}", sender, recipient); // Debug print
+        client.set_alert_threshold(&sender, &token_id, &100i128);
+        let message = String::from_str(&env, "Rent");
+        client.transfer(&token_id, &sender, &recipient, &99i128, &message, &None);
+        let alert_topics: Vec<Val> = (symbol_short!("alert"), sender.clone()).into_val(&env);
+        let alerts = |env: &Env| env.events().all().iter().filter(|(_, topics, _)| *topics == alert_topics).count();
+        assert_eq!(alerts(&env), 0);
 
-        env.ledger().set(LedgerInfo {
-            timestamp: 100000,
-            protocol_version: 20,
-            sequence_number: 123,
-            network_id: Default::default(),
-            base_reserve: 10,
-            min_temp_entry_ttl: 10,
-            min_persistent_entry_ttl: 10,
-            max_entry_ttl: 3110400,
+        client.transfer(&token_id, &sender, &recipient, &100i128, &message, &None);
+        assert_eq!(alerts(&env), 1);
+
+        // Payroll runs and partial request payments are outgoing transfers too
+        let payroll_id = client.create_payroll(&sender, &token_id, &vec![&env, (recipient.clone(), 150i128)], &10u64);
+        env.ledger().with_mut(|li| li.timestamp = 10);
+        client.run_payroll(&payroll_id);
+        assert_eq!(alerts(&env), 2);
+        let request_id = client.request_payment(&recipient, &sender, &token_id, &300i128, &message, &None);
+        client.pay_request_partial(&sender, &request_id, &120i128);
+        assert_eq!(alerts(&env), 3);
+
+        // So is funding an escrow
+        client.send_gift(&sender, &recipient, &token_id, &200i128, &100u64, &message);
+        assert_eq!(alerts(&env), 4);
+        client.create_htlc(&sender, &recipient, &token_id, &200i128, &BytesN::from_array(&env, &[1u8; 32]), &100u64);
+        assert_eq!(alerts(&env), 5);
+    }
+
+    #[test]
+    fn test_net_settle() {
+        let (env, client, token_id) = setup_test_env();
+        let clearer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let alice = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let bob = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let carol = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &clearer);
+        fund(&env, &token_id, &bob);
+
+        let positions = vec![
+            &env,
+            (alice.clone(), 100i128),
+            (bob.clone(), 30i128),
+            (alice.clone(), -40i128),
+            (bob.clone(), -50i128),
+            (carol.clone(), 10i128),
+            (carol.clone(), -10i128),
+        ];
+        assert_eq!(client.net_settle(&token_id, &clearer, &positions), 2);
+        assert_eq!(client.balance(&token_id, &alice), 60i128);
+        assert_eq!(client.balance(&token_id, &bob), INITIAL_MINT_AMOUNT - 20i128);
+        assert_eq!(client.balance(&token_id, &carol), 0i128);
+        assert_eq!(client.balance(&token_id, &clearer), INITIAL_MINT_AMOUNT - 40i128);
+    }
+
+    #[test]
+    fn test_attachments() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let receipt: BytesN<32> = env.crypto().sha256(&Bytes::from_slice(&env, b"receipt.pdf")).into();
+        let msg_id = client.send_message(&sender, &recipient, &String::from_str(&env, "Invoice attached"), &false, &vec![&env, receipt.clone()]);
+        assert_eq!(client.get_attachments(&msg_id), vec![&env, receipt.clone()]);
+
+        client.transfer(&token_id, &sender, &recipient, &10i128, &String::from_str(&env, "Paid"), &Some(TransferOptions { idempotency_key: None, metadata: None, attachments: vec![&env, receipt.clone()] }));
+        assert_eq!(client.get_transaction_history(&sender).get(0).unwrap().attachments, vec![&env, receipt.clone()]);
+
+        let too_many = vec![&env, receipt.clone(), receipt.clone(), receipt.clone(), receipt.clone(), receipt.clone(), receipt];
+        assert!(client.try_send_message(&sender, &recipient, &String::from_str(&env, "Spam"), &false, &too_many).is_err());
+    }
+
+    #[test]
+    fn test_transfer_with_memo() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let exchange = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        client.transfer_with_memo(&token_id, &sender, &exchange, &25i128, &Memo::Id(48213u64), &None);
+        client.transfer(&token_id, &sender, &exchange, &5i128, &String::from_str(&env, "Tip"), &None);
+
+        let history = client.get_transaction_history(&sender);
+        assert_eq!(history.get(0).unwrap().message, Memo::Id(48213u64));
+        assert_eq!(history.get(1).unwrap().message, Memo::Text(String::from_str(&env, "Tip")));
+        assert_eq!(client.balance(&token_id, &exchange), 30i128);
+    }
+
+    #[test]
+    fn test_designated_processor() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let outsider = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        client.create_recurring_payment(&owner, &merchant, &200i128, &100u64, &String::from_str(&env, "Membership"));
+        client.set_recurring_processor(&owner, &Some(merchant.clone()));
+        env.ledger().with_mut(|li| li.timestamp = 100);
+
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.balance(&token_id, &merchant), 0i128);
+        assert!(client.try_process_recurring_for(&outsider, &owner, &token_id).is_err());
+        assert!(client.process_recurring_for(&merchant, &owner, &token_id));
+        assert_eq!(client.balance(&token_id, &merchant), 200i128);
+    }
+
+    #[test]
+    fn test_global_stats() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        client.transfer(&token_id, &sender, &recipient, &30i128, &String::from_str(&env, "Lunch"), &None);
+        client.multi_transfer(&token_id, &sender, &vec![&env, (recipient.clone(), 5i128), (recipient.clone(), 15i128)], &Memo::Text(String::from_str(&env, "Split")), &None);
+        client.create_recurring_payment(&sender, &recipient, &10i128, &100u64, &String::from_str(&env, "Weekly"));
+        let preimage = Bytes::from_slice(&env, b"secret");
+        let htlc_id = client.create_htlc(&sender, &recipient, &token_id, &40i128, &env.crypto().sha256(&preimage).into(), &1000u64);
+
+        let stats = client.get_global_stats();
+        assert_eq!(stats.total_payments, 3u64);
+        assert_eq!(stats.volume.get(token_id.clone()), Some(50i128));
+        assert_eq!(stats.active_plans, 1u32);
+        assert_eq!(stats.open_escrows, 1u32);
+
+        client.withdraw_htlc(&htlc_id, &preimage);
+        let stats = client.get_global_stats();
+        assert_eq!(stats.open_escrows, 0u32);
+        assert_eq!(stats.volume.get(token_id), Some(90i128));
+    }
+
+    #[test]
+    fn test_migrate_v1_to_v2() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+        client.initialize(&admin);
+
+        // Seed a v1 history vector directly, then add one payment in the v2 layout
+        let legacy = |amount: i128, text: &str| PaymentV1 {
+            from: owner.clone(),
+            to: recipient.clone(),
+            amount,
+            message: String::from_str(&env, text),
+        };
+        env.as_contract(&client.address, || {
+            let v1 = vec![&env, legacy(1i128, "First"), legacy(2i128, "Second"), legacy(3i128, "Third")];
+            env.storage().persistent().set(&(symbol_short!("payments"), owner.clone()), &v1);
         });
+        client.transfer(&token_id, &owner, &recipient, &4i128, &String::from_str(&env, "Fourth"), &None);
+
+        let amounts = |history: Vec<Payment>| {
+            let mut amounts = Vec::new(&env);
+            for payment in history.iter() {
+                amounts.push_back(payment.amount);
+            }
+            amounts
+        };
+        assert_eq!(amounts(client.get_transaction_history(&owner)), vec![&env, 1i128, 2, 3, 4]);
+        assert_eq!(client.migrate_v1_to_v2(&owner, &2u32), 1u32);
+        assert_eq!(amounts(client.get_transaction_history(&owner)), vec![&env, 1i128, 2, 3, 4]);
+        assert_eq!(client.migrate_v1_to_v2(&owner, &2u32), 0u32);
+        assert_eq!(client.migrate_v1_to_v2(&owner, &2u32), 0u32);
+        assert_eq!(amounts(client.get_transaction_history(&owner)), vec![&env, 1i128, 2, 3, 4]);
+        assert_eq!(client.get_transaction_history(&owner).get(0).unwrap().message, Memo::Text(String::from_str(&env, "First")));
+    }
+
+    #[test]
+    fn test_freeze_address() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        client.initialize(&admin);
+
+        let message = String::from_str(&env, "Blocked");
+        client.freeze_address(&recipient);
+        assert!(client.is_frozen(&recipient));
+        let result = client.try_transfer(&token_id, &sender, &recipient, &10i128, &message, &None);
+        assert_eq!(result, Err(Ok(Error::Frozen.into())));
+        assert_eq!(client.try_deposit(&recipient, &token_id, &1i128), Err(Ok(Error::Frozen.into())));
+        let quote = client.quote_transfer(&sender, &recipient, &token_id, &10i128);
+        assert!(!quote.allowed);
+        assert_eq!(quote.reason, symbol_short!("frozen"));
+
+        // A frozen charity receives no round-up donation
+        let charity = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let other = <soroban_sdk::Address as TestAddress>::generate(&env);
+        client.set_roundup(&sender, &charity, &100i128);
+        client.freeze_address(&charity);
+        assert!(client.transfer(&token_id, &sender, &other, &10i128, &message, &None));
+        assert_eq!(client.balance(&token_id, &charity), 0i128);
+
+        client.unfreeze_address(&recipient);
+        assert!(client.transfer(&token_id, &sender, &recipient, &10i128, &message, &None));
+        assert_eq!(client.balance(&token_id, &recipient), 10i128);
+    }
 
+    #[test]
+    fn test_prepaid_plan() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &owner);
+
+        client.create_recurring_payment(&owner, &recipient, &100i128, &10u64, &String::from_str(&env, "Gym"));
+        assert_eq!(client.prepay_plan(&owner, &token_id, &250i128), 250i128);
+        let other_token = create_token_contract(&env);
+        assert!(client.try_prepay_plan(&owner, &other_token, &250i128).is_err());
+
+        // Charges come out of the escrow without any signature from the owner, and charging
+        // the plan in another token is skipped rather than counted as a failure
+        env.set_auths(&[]);
+        env.ledger().with_mut(|li| li.timestamp = 10);
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            client.process_recurring_payments(&other_token);
+        }
+        env.ledger().with_mut(|li| li.timestamp = 10);
         client.process_recurring_payments(&token_id);
+        env.ledger().with_mut(|li| li.timestamp = 20);
+        client.process_recurring_payments(&token_id);
+        assert_eq!(client.get_prepaid(&owner, &token_id), 50i128);
 
         env.mock_all_auths();
+        assert_eq!(client.balance(&token_id, &recipient), 200i128);
+        assert_eq!(client.withdraw_prepaid(&owner, &token_id), 50i128);
+        assert_eq!(client.balance(&token_id, &owner), INITIAL_MINT_AMOUNT - 200i128);
+
+        // Keepers of a prepaid plan are rewarded out of the prepaid balance
+        let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+        client.set_keeper_reward(&owner, &KeeperReward::Flat(5i128));
+        client.prepay_plan(&owner, &token_id, &105i128);
+        env.ledger().with_mut(|li| li.timestamp = 30);
+        assert!(client.process_recurring_for(&keeper, &owner, &token_id));
+        let auths = env.auths();
+        assert_eq!(auths.len(), 1);
+        assert_eq!(auths[0].0, keeper);
+        assert_eq!(client.balance(&token_id, &keeper), 5i128);
+        assert_eq!(client.get_prepaid(&owner, &token_id), 0i128);
+    }
+
+    #[test]
+    fn test_conditional_payment() {
+        let (env, client, token_id) = setup_test_env();
+        let client_addr = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let contractor = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &client_addr);
+        let milestones = env.register_contract(None, MockMilestones);
+        let milestones_client = MockMilestonesClient::new(&env, &milestones);
+
+        let args: Vec<Val> = vec![&env, 1u32.into_val(&env)];
+        let paid = client.create_conditional_payment(&client_addr, &contractor, &token_id, &300i128, &Condition { contract: milestones.clone(), function: Symbol::new(&env, "is_done"), args }, &1000u64);
+        assert!(!client.execute_conditional_payment(&paid));
+        milestones_client.complete(&1u32);
+        assert!(client.execute_conditional_payment(&paid));
+        assert_eq!(client.balance(&token_id, &contractor), 300i128);
+
+        let refunded = client.create_conditional_payment(&client_addr, &contractor, &token_id, &50i128, &Condition { contract: milestones.clone(), function: Symbol::new(&env, "is_done"), args: vec![&env, 2u32.into_val(&env)] }, &1000u64);
+        assert!(client.try_refund_conditional_payment(&refunded).is_err());
+        env.ledger().with_mut(|li| li.timestamp = 1000);
+        client.refund_conditional_payment(&refunded);
+        assert_eq!(client.balance(&token_id, &client_addr), INITIAL_MINT_AMOUNT - 300i128);
+    }
+
+    #[test]
+    fn test_split_route() {
+        let (env, client, token_id) = setup_test_env();
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let artist = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let label = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let buyer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &buyer);
+
+        assert!(client.try_create_split_route(&owner, &vec![&env, (artist.clone(), 7000u32), (label.clone(), 2000u32)]).is_err());
+        assert!(client.try_create_split_route(&owner, &vec![&env, (artist.clone(), u32::MAX), (label.clone(), 10_001u32)]).is_err());
+        let route_id = client.create_split_route(&owner, &vec![&env, (artist.clone(), 7000u32), (label.clone(), 3000u32)]);
+        client.transfer_via_route(&buyer, &route_id, &token_id, &101i128, &Memo::Text(String::from_str(&env, "Album")));
+        assert_eq!(client.balance(&token_id, &artist), 70i128);
+        assert_eq!(client.balance(&token_id, &label), 31i128);
+        assert_eq!(client.get_transaction_history(&buyer).len(), 2);
+    }
+
+    #[test]
+    fn test_checked_transfers() {
+        let (env, client, token_id) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let alice = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let bob = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        client.initialize(&admin);
+        client.set_rate_limit(&3u32, &100u32);
+        client.set_limits(&10u32, &8u32, &100u32);
+        let message = String::from_str(&env, "Payout");
+
+        assert_eq!(client.transfer_checked(&token_id, &sender, &alice, &(INITIAL_MINT_AMOUNT + 1), &message), TransferStatus::Failed);
+        assert_eq!(client.transfer_checked(&token_id, &sender, &alice, &0i128, &message), TransferStatus::Invalid);
+        assert_eq!(client.transfer_checked(&token_id, &sender, &alice, &10i128, &message), TransferStatus::Sent);
+
+        let recipients = vec![&env, (alice.clone(), 5i128), (bob.clone(), INITIAL_MINT_AMOUNT), (bob.clone(), 7i128)];
+        let statuses = client.multi_transfer_checked(&token_id, &sender, &recipients, &message);
+        assert_eq!(statuses, vec![&env, TransferStatus::Sent, TransferStatus::Failed, TransferStatus::Sent]);
+        assert_eq!(client.balance(&token_id, &alice), 15i128);
+        assert_eq!(client.balance(&token_id, &bob), 7i128);
+
+        // Failed legs did not use up the rate limit; only the three sent ones did
+        assert_eq!(client.transfer_checked(&token_id, &sender, &alice, &1i128, &message), TransferStatus::RateLimited);
+        let long_message = String::from_str(&env, "Far too long");
+        assert!(client.try_transfer_checked(&token_id, &sender, &alice, &1i128, &long_message).is_err());
+        assert!(client.try_transfer_checked_with_memo(&token_id, &sender, &alice, &1i128, &Memo::Text(long_message)).is_err());
+        assert_eq!(client.transfer_checked_with_memo(&token_id, &sender, &alice, &1i128, &Memo::Id(4)), TransferStatus::RateLimited);
+    }
+
+    #[test]
+    fn test_receipts() {
+        let (env, client, token_id) = setup_test_env();
+        let payer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &payer);
+        env.ledger().with_mut(|li| li.timestamp = 500);
+
+        let memo = Memo::Text(String::from_str(&env, "Order 17"));
+        let payment_id = client.pay_from_contract(&payer, &merchant, &token_id, &80i128, &memo);
+        env.set_auths(&[]);
+        let receipt = client.issue_receipt(&payment_id);
+        assert_eq!(client.issue_receipt(&payment_id), receipt);
+
+        let expected = (payer, merchant, token_id, 80i128, 500u64, memo).to_xdr(&env);
+        let expected: BytesN<32> = env.crypto().sha256(&expected).into();
+        assert_eq!(receipt, expected);
+        assert!(client.verify_receipt(&payment_id, &receipt));
+        assert!(!client.verify_receipt(&payment_id, &BytesN::from_array(&env, &[0u8; 32])));
+    }
+
+    #[test]
+    fn test_inbox_pages_and_unread() {
+        let (env, client, _) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+        for text in ["one", "two", "three"] {
+            client.send_message(&sender, &owner, &String::from_str(&env, text), &false, &vec![&env]);
+        }
+        assert_eq!(client.get_unread_count(&owner), 3u32);
+
+        let page = client.get_messages_page(&owner, &1u32, &5u32);
+        assert_eq!(page.len(), 2);
+        assert_eq!(page.get(0).unwrap().text, String::from_str(&env, "two"));
+
+        client.mark_inbox_read(&owner);
+        assert_eq!(client.get_unread_count(&owner), 0u32);
+        client.send_message(&sender, &owner, &String::from_str(&env, "four"), &false, &vec![&env]);
+        assert_eq!(client.get_unread_count(&owner), 1u32);
+    }
+
+    #[test]
+    fn test_transfer_sponsored() {
+        let (env, client, token_id) = setup_test_env();
+        let relayer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+
+        let transfer = SponsoredTransfer {
+            to: recipient.clone(),
+            token_id: token_id.clone(),
+            amount: 100i128,
+            memo: Memo::Text(String::from_str(&env, "Gasless")),
+            tip: 3i128,
+        };
+        client.transfer_sponsored(&relayer, &sender, &transfer);
+
+        // The sender signed over the relayer and the tip, not just the payment
+        let (_, invocation) = env.auths().into_iter().find(|(address, _)| *address == sender).unwrap();
+        assert_eq!(invocation.function, AuthorizedFunction::Contract((
+            client.address.clone(),
+            Symbol::new(&env, "transfer_sponsored"),
+            (relayer.clone(), transfer).into_val(&env),
+        )));
+        assert_eq!(client.balance(&token_id, &recipient), 97i128);
+        assert_eq!(client.balance(&token_id, &relayer), 3i128);
+        assert!(client.try_transfer_sponsored(&relayer, &sender, &SponsoredTransfer {
+            to: recipient.clone(),
+            token_id: token_id.clone(),
+            amount: 10i128,
+            memo: Memo::Text(String::from_str(&env, "x")),
+            tip: 11i128,
+        }).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        client.initialize(&admin);
+        client.set_limits(&2u32, &8u32, &1u32);
+
+        let three = vec![&env, (recipient.clone(), 1i128), (recipient.clone(), 1i128), (recipient.clone(), 1i128)];
+        let result = client.try_multi_transfer(&token_id, &sender, &three, &Memo::Text(String::from_str(&env, "Split")), &None);
+        assert_eq!(result, Err(Ok(Error::TooManyRecipients.into())));
+        let result = client.try_send_message(&sender, &recipient, &String::from_str(&env, "Far too long"), &false, &vec![&env]);
+        assert_eq!(result, Err(Ok(Error::MessageTooLong.into())));
+        assert_eq!(client.try_process_recurring_batch(&token_id, &None, &5u32), Err(Ok(Error::BatchTooLarge.into())));
+
+        let result = client.try_transfer(&token_id, &sender, &recipient, &1i128, &String::from_str(&env, "Far too long"), &None);
+        assert_eq!(result, Err(Ok(Error::MessageTooLong.into())));
+
+        client.multi_transfer(&token_id, &sender, &vec![&env, (recipient.clone(), 1i128), (recipient.clone(), 2i128)], &Memo::Text(String::from_str(&env, "Split")), &None);
+        assert_eq!(client.balance(&token_id, &recipient), 3i128);
+
+        // History past the limit is truncated to the latest entries and read in pages
         let history = client.get_transaction_history(&sender);
         assert_eq!(history.len(), 1);
-        assert_eq!(history.get(0).unwrap().amount, 10i128);
-        assert_eq!(history.get(0).unwrap().message, String::from_str(&env, "Daily payment"));
-        println!("Transaction history for sender: {:?}", history); // Debug print
+        assert_eq!(history.get(0).unwrap().amount, 2i128);
+        assert_eq!(client.get_transaction_history_page(&sender, &0u32, &1u32).get(0).unwrap().amount, 1i128);
+        assert_eq!(client.try_get_transaction_history_page(&sender, &0u32, &2u32), Err(Ok(Error::BatchTooLarge.into())));
     }
 
     #[test]
-    fn test_multi_transfer() {
+    fn test_net_position() {
+        let (env, client, token_id) = setup_test_env();
+        let alice = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let bob = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &alice);
+        fund(&env, &token_id, &bob);
+
+        client.transfer(&token_id, &alice, &bob, &120i128, &String::from_str(&env, "Rent"), &None);
+        client.transfer(&token_id, &bob, &alice, &45i128, &String::from_str(&env, "Groceries"), &None);
+
+        assert_eq!(client.get_net_position(&alice, &bob, &token_id), 75i128);
+        assert_eq!(client.get_net_position(&bob, &alice, &token_id), -75i128);
+    }
+
+    #[test]
+    fn test_vouchers() {
+        let (env, client, token_id) = setup_test_env();
+        let issuer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let shopper = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &issuer);
+
+        let code = |text: &str| Bytes::from_slice(&env, text.as_bytes());
+        let hash = |text: &str| -> BytesN<32> { env.crypto().sha256(&code(text)).into() };
+        client.issue_vouchers(&issuer, &token_id, &25i128, &vec![&env, hash("GIFT-1"), hash("GIFT-2"), hash("GIFT-3")], &1000u64);
+        assert_eq!(client.balance(&token_id, &issuer), INITIAL_MINT_AMOUNT - 75i128);
+
+        assert_eq!(client.redeem_voucher(&code("GIFT-2"), &shopper), 25i128);
+        assert!(client.try_redeem_voucher(&code("GIFT-2"), &shopper).is_err());
+        assert!(client.try_redeem_voucher(&code("GIFT-9"), &shopper).is_err());
+        assert_eq!(client.balance(&token_id, &shopper), 25i128);
+
+        assert!(client.try_reclaim_voucher(&hash("GIFT-1")).is_err());
+        env.ledger().with_mut(|li| li.timestamp = 1001);
+        assert_eq!(client.reclaim_voucher(&hash("GIFT-1")), 25i128);
+        assert_eq!(client.get_global_stats().open_escrows, 1u32);
+    }
+
+    #[test]
+    fn test_transfer_v2() {
         let (env, client, token_id) = setup_test_env();
         let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
-        let user2 = <soroban_sdk::Address as TestAddress>::generate(&env);
-        let user3 = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &sender);
+        env.ledger().with_mut(|li| li.timestamp = 42);
 
-        // Construct the recipients vector
-        let recipients = vec![
-            &env,
-            (user2.clone(), 10i128),
-            (user3.clone(), 20i128),
-        ];
+        let message = String::from_str(&env, "Invoice 9");
+        let receipt = client.transfer_v2(&token_id, &sender, &recipient, &60i128, &message, &None);
+        let payment_id = receipt.payment_id.unwrap();
+        assert_eq!(receipt, TransferReceipt { payment_id: Some(payment_id), intent_id: None, net_amount: 60i128, fee: 0, timestamp: 42 });
+        assert_eq!(client.get_payment(&payment_id).amount, 60i128);
 
-        env.mock_all_auths();
-        let result = client.multi_transfer(&token_id, &sender, &recipients, &String::from_str(&env, "Multi transfer"));
-        assert!(result);
+        let receipt = client.transfer_v2_with_memo(&token_id, &sender, &recipient, &15i128, &Memo::Id(9), &None);
+        assert_eq!(client.get_payment(&receipt.payment_id.unwrap()).message, Memo::Id(9));
+
+        client.set_confirmation_threshold(&sender, &token_id, &100i128, &60u64);
+        let held = client.transfer_v2(&token_id, &sender, &recipient, &500i128, &message, &None);
+        assert_eq!(held.payment_id, None);
+        assert_eq!(client.get_transfer_intent(&held.intent_id.unwrap()).unlock_at, 102u64);
+    }
 
+    #[test]
+    fn test_testutils_payment_events() {
+        let env = Env::default();
         env.mock_all_auths();
-        let history = client.get_transaction_history(&sender);
+        let (client, token_id) = testutils::register_with_token(&env);
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let recipient = <soroban_sdk::Address as TestAddress>::generate(&env);
+        testutils::fund(&env, &token_id, &sender, 500i128);
+        client.initialize(&admin);
+
+        // The default Storage mode emits no payment events, so the stored records are read
+        client.transfer(&token_id, &sender, &recipient, &50i128, &String::from_str(&env, "Helmet"), &None);
+        assert_eq!(testutils::payment_events(&env, &client.address).len(), 1);
+        testutils::assert_payment_event(&env, &client.address, &sender, &recipient, 50i128);
+
+        client.set_history_mode(&HistoryMode::Events);
+        client.transfer(&token_id, &sender, &recipient, &120i128, &String::from_str(&env, "Bike"), &None);
+        assert_eq!(testutils::payment_events(&env, &client.address).len(), 1);
+        testutils::assert_payment_event(&env, &client.address, &sender, &recipient, 120i128);
+    }
+
+    #[test]
+    fn test_scheduled_message() {
+        let (env, client, _) = setup_test_env();
+        let sender = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let owner = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+        client.schedule_message(&sender, &owner, &String::from_str(&env, "Happy birthday!"), &1000u64);
+        client.schedule_message(&sender, &owner, &String::from_str(&env, "See you next year"), &5000u64);
+        assert_eq!(client.get_inbox(&owner).len(), 0);
+
+        env.ledger().with_mut(|li| li.timestamp = 1000);
+        assert_eq!(client.get_unread_count(&owner), 0u32);
+        let inbox = client.get_inbox(&owner);
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox.get(0).unwrap().text, String::from_str(&env, "Happy birthday!"));
+
+        env.ledger().with_mut(|li| li.timestamp = 5000);
+        assert_eq!(client.deliver_due_messages(&owner), 1u32);
+        assert_eq!(client.get_unread_count(&owner), 2u32);
+    }
+
+    #[test]
+    fn test_multi_asset_bundle() {
+        let (env, client, token_id) = setup_test_env();
+        let gov_token = create_token_contract(&env);
+        let dao = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let contributor = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+        fund(&env, &token_id, &dao);
+        testutils::fund(&env, &gov_token, &dao, 15i128);
+
+        client.create_recurring_payment(&dao, &contributor, &500i128, &100u64, &String::from_str(&env, "Contributor pay"));
+        client.set_recurring_legs(&dao, &vec![&env, (gov_token.clone(), 10i128)]);
+
+        env.ledger().with_mut(|li| li.timestamp = 100);
+        assert!(client.process_recurring_for(&keeper, &dao, &token_id));
+        assert_eq!(client.balance(&token_id, &contributor), 500i128);
+        assert_eq!(client.balance(&gov_token, &contributor), 10i128);
+        let history = client.get_transaction_history(&dao);
         assert_eq!(history.len(), 2);
-        assert_eq!(history.get(0).unwrap().amount, 10i128);
-        assert_eq!(history.get(1).unwrap().amount, 20i128);
-        println!("Transaction history for sender after multi-transfer: {:?}", history); // Debug print
+        assert_eq!(history.get(0).unwrap().batch_id, history.get(1).unwrap().batch_id);
+
+        // Only 5 governance tokens remain, so the next cycle pays neither leg
+        env.ledger().with_mut(|li| li.timestamp = 200);
+        assert!(!client.process_recurring_for(&keeper, &dao, &token_id));
+        assert_eq!(client.balance(&token_id, &contributor), 500i128);
+        assert_eq!(client.balance(&gov_token, &dao), 5i128);
+    }
+
+    #[test]
+    fn test_referral_rewards() {
+        let (env, client, token_id) = setup_test_env();
+        let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let referrer = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let user = <soroban_sdk::Address as TestAddress>::generate(&env);
+        let merchant = <soroban_sdk::Address as TestAddress>::generate(&env);
+        testutils::fund(&env, &token_id, &user, 2020i128);
+        client.initialize(&admin);
+        client.set_protocol_fee(&100u32);
+        client.set_referral_reward(&5000u32);
+
+        client.register_referrer(&user, &referrer);
+        assert!(client.try_register_referrer(&user, &merchant).is_err());
+
+        // The 1% fee is charged on top, and the referrer gets half of it
+        let quote = client.quote_transfer(&user, &merchant, &token_id, &1000i128);
+        assert_eq!(quote.fee, 10i128);
+        let receipt = client.transfer_v2(&token_id, &user, &merchant, &1000i128, &String::from_str(&env, "Order"), &None);
+        assert_eq!(receipt.fee, 10i128);
+        assert_eq!(client.balance(&token_id, &merchant), 1000i128);
+        assert_eq!(client.get_referral_rewards(&referrer, &token_id), 5i128);
+        assert_eq!(client.get_collected_fees(&token_id), 5i128);
+
+        // Rewards never exceed what the referred user paid in fees
+        client.transfer(&token_id, &user, &merchant, &1000i128, &String::from_str(&env, "Order"), &None);
+        assert_eq!(client.balance(&token_id, &user), 0i128);
+        assert_eq!(client.get_referral_rewards(&referrer, &token_id), 10i128);
+
+        assert_eq!(client.claim_referral_rewards(&referrer, &token_id), 10i128);
+        assert_eq!(client.balance(&token_id, &referrer), 10i128);
+        assert_eq!(client.get_referral_rewards(&referrer, &token_id), 0i128);
+        assert_eq!(client.withdraw_fees(&token_id), 10i128);
+        assert_eq!(client.balance(&token_id, &admin), 10i128);
     }
 }